@@ -1,25 +1,88 @@
 mod commands;
 mod core;
 mod events;
+mod holders;
+mod metrics;
+mod planner;
+mod rooms;
+mod scheduler;
+mod slash;
 mod util;
+mod voice;
 
+use crate::bot::core::{Departed, Departures};
 use crate::bot::events::Handler;
+use crate::bot::holders::RoleHolders;
+use crate::bot::metrics::Metrics;
+use crate::bot::scheduler::{Queue, Reviews};
+use crate::bot::slash::Slash;
+use crate::bot::util::Reports;
+use crate::bot::voice::VoiceIndex;
 use crate::config::Config;
+use crate::store::{RoomStore, Store};
 use log::warn;
-use serenity::client::Client;
+use serenity::client::{Client, Context};
+use serenity::framework::standard::macros::hook;
 use serenity::framework::StandardFramework;
+use serenity::http::Http;
+use serenity::model::channel::Message;
 use serenity::prelude::TypeMapKey;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
 
 impl TypeMapKey for Config {
     type Value = Config;
 }
 
-pub async fn start(config: Config) {
+impl TypeMapKey for Store {
+    type Value = Arc<dyn RoomStore>;
+}
+
+#[hook]
+// dynamic_prefix gets the prefix of the guild a message was sent in. Guilds that haven't set one
+// with the prefix command use the prefix in the config.
+async fn dynamic_prefix(ctx: &Context, msg: &Message) -> Option<String> {
+    if let Some(guild_id) = msg.guild_id {
+        if let Some(serving) = util::store(ctx).await.find(guild_id) {
+            if serving.prefix.is_some() {
+                return serving.prefix;
+            }
+        }
+    }
+
+    let data = ctx.data.read().await;
+    data.get::<Config>().map(|config| config.prefix.clone())
+}
+
+pub async fn start(config: Config, store: Arc<dyn RoomStore>) {
+    // The bot's ID is needed so mentioning it always works as a prefix.
+    let http = Http::new_with_token(&config.token);
+    let bot_id = match http.get_current_user().await {
+        Ok(user) => user.id,
+        Err(e) => {
+            warn!("Failed to login, is the token correct?\n{}", e);
+            return;
+        }
+    };
+
+    // Slash commands are registered under the bot's application, without it there are only the
+    // prefix commands.
+    let slash = match http.get_current_application_info().await {
+        Ok(info) => Some(Slash::new(&config.token, info.id)),
+        Err(why) => {
+            warn!("Failed to get the application, slash commands are disabled\n{}", why);
+            None
+        }
+    };
+
+    // There are no static prefixes, every guild's prefix comes from dynamic_prefix.
     let framework = StandardFramework::new()
         .group(&commands::ADMINCOMMANDS_GROUP)
         .group(&commands::COMMANDS_GROUP)
         .configure(|c| {
-            c.prefix(&config.prefix);
+            c.prefixes(Vec::<String>::new());
+            c.dynamic_prefix(dynamic_prefix);
+            c.on_mention(Some(bot_id));
             c.allow_dm(false);
             c.case_insensitivity(true);
             return c;
@@ -34,6 +97,16 @@ pub async fn start(config: Config) {
     {
         let mut data = client.data.write().await;
         data.insert::<Config>(config);
+        data.insert::<Store>(store);
+        data.insert::<Departures>(Arc::new(Mutex::new(Departed::default())));
+        data.insert::<Reviews>(Arc::new(Mutex::new(Queue::default())));
+        data.insert::<Reports>(Arc::new(Mutex::new(HashMap::new())));
+        data.insert::<Metrics>(Arc::new(Metrics::default()));
+        data.insert::<RoleHolders>(Arc::new(Mutex::new(RoleHolders::default())));
+        data.insert::<VoiceIndex>(Arc::new(RwLock::new(VoiceIndex::default())));
+        if let Some(slash) = slash {
+            data.insert::<Slash>(Arc::new(slash));
+        }
     }
 
     if let Err(e) = client.start().await {
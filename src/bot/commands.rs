@@ -1,5 +1,5 @@
-use crate::bot::util;
-use crate::config::{Config, Room, Serving};
+use crate::bot::{core, planner, rooms, scheduler, util};
+use crate::config::{Access, Config, PermissionTemplate, Room, RoomKind, Serving};
 use log::{info, warn};
 use serenity::client::Context;
 use serenity::framework::standard::macros::{check, command, group};
@@ -9,7 +9,10 @@ use serenity::framework::standard::*;
 use serenity::model::prelude::*;
 
 #[group()]
-#[commands(add, remove)]
+#[commands(
+    add, auto, hub, remove, category, mode, permissions, allowlist, denylist, grace, dryrun,
+    prefix, cleanup, sync, adopt, stats
+)]
 #[checks(auth)]
 pub struct AdminCommands;
 
@@ -98,36 +101,32 @@ Failed Command Execution
 
 // check_perms compliments auth. It makes sure that the user running a command has administrator and
 // or "manage channels" (bits: 16)
-fn check_perms(perms: &Permissions) -> bool {
+pub fn check_perms(perms: &Permissions) -> bool {
     return perms.administrator() || perms.manage_channels();
 }
 
 #[command]
 // link allows users to link a text-channel and voice-channel together. When a voice and text
 // channel are linked together it's called a "Room" and every guild has it's own vector of rooms
-// stored in the config.
+// kept in the RoomStore. Linking a channel that's already part of a room adds the other channel
+// to that room, so a voice channel can reveal several text-channels and several voice channels
+// can share one text-channel.
 // args = [#text-channel, voice channel ID] or [voice channel ID, #text-channel]
 async fn add(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
-    let mut data = ctx.data.write().await;
-    let mut config = data.get_mut::<Config>().unwrap().clone();
+    let store = util::store(ctx).await;
     let mut serving;
 
-    // Get the vector of rooms for this guild.
-    if let Some(_s) = config.serving.get(msg.guild_id.unwrap().as_u64()) {
-        serving = _s.clone();
-    } else {
-        // if they don't have on then make one.
-        if let Some(guild_id) = msg.guild_id {
-            serving = Serving {
-                guild_id,
-                rooms: Vec::<Room>::new(),
-            };
-        } else {
-            let res = "Please use this command in a guild.".to_string();
-            util::bad(ctx, msg).await;
-            util::respond(ctx, msg, &res).await;
-            return Ok(());
-        }
+    if msg.guild_id.is_none() {
+        let res = "Please use this command in a guild.".to_string();
+        util::bad(ctx, msg).await;
+        util::respond(ctx, msg, &res).await;
+        return Ok(());
+    }
+
+    // Get the vector of rooms for this guild, if they don't have one then make one.
+    match util::serving(ctx, msg, store.as_ref()).await {
+        Some(_serving) => serving = _serving,
+        None => return Ok(()),
     }
 
     // parse_channels will get us the channels the user is referring to in their message.
@@ -136,13 +135,13 @@ async fn add(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
     let voice;
 
     match channels {
-        Some((_voice, _text)) => {
+        Some((Channel::Guild(_voice), Channel::Guild(_text))) => {
             voice = _voice;
             text = _text;
         }
         // If parse_channels didn't return anything then the user didn't provide two channels or
         // mistakenly two of the same type of channel.
-        None => {
+        _ => {
             let res = "Please mention a text channel and ID of the voice channel.".to_string();
             util::bad(ctx, msg).await;
             util::respond(ctx, msg, &res).await;
@@ -150,34 +149,124 @@ async fn add(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
         }
     }
 
-    // Iterate through the guild's rooms and make sure the channels they provided aren't already
-    // linked with something else.
-    for room in serving.rooms.iter() {
-        if room.voice_id == voice.id() || room.text_id == text.id() {
+    // Finally link the channels together and establish a room.
+    if let Err(why) = rooms::link(&mut serving, &voice, &text) {
+        util::bad(ctx, msg).await;
+        util::respond(ctx, msg, &why.to_string()).await;
+        return Ok(());
+    }
+
+    // Save it to the store
+    if let Err(why) = store.put(&serving) {
+        warn!("Failed to save the rooms of {} because\n{}", serving.guild_id, why);
+        let res = format!("Failed to save the room, {}", why);
+        util::bad(ctx, msg).await;
+        util::respond(ctx, msg, &res).await;
+        return Ok(());
+    }
+    // React to their message to let them know everything went right.
+    util::good(ctx, msg).await;
+    Ok(())
+}
+
+#[command]
+// auto makes a voice channel an auto-room. Instead of linking an existing text-channel the bot
+// makes one next to the voice channel when the first person joins and deletes it once the last
+// person leaves. With "archive" the text-channel is kept hidden instead of deleted.
+// args = [voice channel ID] or [voice channel ID, archive]
+async fn auto(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let store = util::store(ctx).await;
+    let mut serving;
+    match util::serving(ctx, msg, store.as_ref()).await {
+        Some(_serving) => serving = _serving,
+        None => return Ok(()),
+    }
+
+    let voice;
+    match args.single::<u64>() {
+        Ok(id) => match ChannelId(id).to_channel(ctx).await {
+            Ok(Channel::Guild(_voice)) => voice = _voice,
+            _ => {
+                let res = "That isn't a voice channel.".to_string();
+                util::bad(ctx, msg).await;
+                util::respond(ctx, msg, &res).await;
+                return Ok(());
+            }
+        },
+        Err(_) => {
+            let res = "Please provide the ID of a voice channel.".to_string();
             util::bad(ctx, msg).await;
-            let res;
-            if room.voice_id == voice.id() {
-                res = "That voice channel is already linked with something.".to_string();
-            } else {
-                res = "That text channel is already linked with something.".to_string();
+            util::respond(ctx, msg, &res).await;
+            return Ok(());
+        }
+    }
+
+    let archive = args.rest().trim().eq_ignore_ascii_case("archive");
+
+    if let Err(why) = rooms::auto_room(&mut serving, &voice, archive) {
+        util::bad(ctx, msg).await;
+        util::respond(ctx, msg, &why.to_string()).await;
+        return Ok(());
+    }
+
+    if let Err(why) = store.put(&serving) {
+        warn!("Failed to save the rooms of {} because\n{}", serving.guild_id, why);
+        let res = format!("Failed to save the room, {}", why);
+        util::bad(ctx, msg).await;
+        util::respond(ctx, msg, &res).await;
+        return Ok(());
+    }
+
+    util::good(ctx, msg).await;
+    Ok(())
+}
+
+#[command]
+// hub makes a voice channel a hub. Whoever joins it gets their own voice channel and text-channel
+// made for them and is moved into it. Both are deleted once everyone left. Use remove with the
+// hub's ID to stop it.
+// args = [voice channel ID]
+async fn hub(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let store = util::store(ctx).await;
+    let mut serving;
+    match util::serving(ctx, msg, store.as_ref()).await {
+        Some(_serving) => serving = _serving,
+        None => return Ok(()),
+    }
+
+    let voice;
+    match args.single::<u64>() {
+        Ok(id) => match ChannelId(id).to_channel(ctx).await {
+            Ok(Channel::Guild(_voice)) => voice = _voice,
+            _ => {
+                let res = "That isn't a voice channel.".to_string();
+                util::bad(ctx, msg).await;
+                util::respond(ctx, msg, &res).await;
+                return Ok(());
             }
+        },
+        Err(_) => {
+            let res = "Please provide the ID of a voice channel.".to_string();
+            util::bad(ctx, msg).await;
             util::respond(ctx, msg, &res).await;
             return Ok(());
         }
     }
 
-    // Finally link the channels together and establish a room.
-    let room = Room {
-        voice_id: voice.id(),
-        text_id: text.id(),
-    };
+    if let Err(why) = rooms::add_hub(&mut serving, &voice) {
+        util::bad(ctx, msg).await;
+        util::respond(ctx, msg, &why.to_string()).await;
+        return Ok(());
+    }
+
+    if let Err(why) = store.put(&serving) {
+        warn!("Failed to save the rooms of {} because\n{}", serving.guild_id, why);
+        let res = format!("Failed to save the hub, {}", why);
+        util::bad(ctx, msg).await;
+        util::respond(ctx, msg, &res).await;
+        return Ok(());
+    }
 
-    // Save it to the config
-    serving.rooms.push(room);
-    config.serving.insert(*serving.guild_id.as_u64(), serving);
-    config.save();
-    data.insert::<Config>(config.clone());
-    // React to their message to let them know everything went right.
     util::good(ctx, msg).await;
     Ok(())
 }
@@ -186,17 +275,14 @@ async fn add(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
 // unlink will remove a link between a text-channel and voice-channel
 // args can be a vector of #text-channels, voice channel IDs, or a combination. It
 // will unlinked all the channels provided (so essentially you can chain the channels.)
+// A room is removed once it has no voice channels or no text-channels left.
 async fn remove(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let store = util::store(ctx).await;
     let mut serving;
-    let mut config;
-    {
-        let mut data = ctx.data.write().await;
-        config = data.get_mut::<Config>().unwrap().clone();
-    }
 
     // Get all the rooms for the guild.
-    if let Some(_s) = config.serving.get(msg.guild_id.unwrap().as_u64()) {
-        serving = _s.clone();
+    if let Some(_s) = store.find(msg.guild_id.unwrap()) {
+        serving = _s;
     } else {
         util::good(ctx, msg).await;
         return Ok(());
@@ -225,25 +311,21 @@ async fn remove(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
 
     // List all the channels successfully unlinked
     let mut unlinked = String::new();
+    // The rooms that were removed along the way
+    let mut removed = Vec::<Room>::new();
     // List all the channels failed to unlink
     let mut not_unlinked = String::new();
 
     // Go through all the channels and unlink them.
     for channel_id in channel_ids {
-        let mut i = 0;
         match channel_id.to_channel(ctx).await {
             Ok(channel) => {
-                for room in serving.rooms.clone().iter() {
-                    if room.text_id == channel.id() {
-                        if unlinked.is_empty() {
-                            unlinked.push_str("Unlinked: \n");
-                        }
-
-                        serving.rooms.remove(i);
-                        unlinked.push_str(format!(" - <#{}>\n", channel.id()).as_str());
-                        break;
+                if let Some(_removed) = rooms::unlink(&mut serving, channel.id()) {
+                    removed.extend(_removed);
+                    if unlinked.is_empty() {
+                        unlinked.push_str("Unlinked: \n");
                     }
-                    i += 1;
+                    unlinked.push_str(format!(" - <#{}>\n", channel.id()).as_str());
                 }
             }
             Err(_) => {
@@ -255,13 +337,15 @@ async fn remove(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
         }
     }
 
-    // After all the channels are unlinked save it back into the config.
-    {
-        let mut data = ctx.data.write().await;
-        config.serving.insert(*serving.guild_id.as_u64(), serving);
-        config.save();
-        data.insert::<Config>(config.clone());
+    // After all the channels are unlinked save it back into the store.
+    if let Err(why) = store.put(&serving) {
+        warn!("Failed to save the rooms of {} because\n{}", serving.guild_id, why);
+        let res = format!("Failed to save the rooms, {}", why);
+        util::bad(ctx, msg).await;
+        util::respond(ctx, msg, &res).await;
+        return Ok(());
     }
+    core::forget_rooms(ctx, serving.guild_id, &removed).await;
 
     // If all the channels were unlinked successfully
     if !unlinked.is_empty() && not_unlinked.is_empty() {
@@ -283,19 +367,812 @@ async fn remove(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
     Ok(())
 }
 
+#[command]
+// category links a whole category. Every voice channel in it is paired with the text channel in it
+// named after the voice channel, including channels created later. The pattern changes which text
+// channel is picked, "{name}" in it is replaced with the voice channel's name. Use remove with the
+// category ID to unlink it.
+// args = [category ID] or [category ID, pattern]
+async fn category(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let store = util::store(ctx).await;
+    let mut serving;
+    match util::serving(ctx, msg, store.as_ref()).await {
+        Some(_serving) => serving = _serving,
+        None => return Ok(()),
+    }
+
+    let category;
+    match args.single::<u64>() {
+        Ok(id) => match ChannelId(id).to_channel(ctx).await {
+            Ok(Channel::Category(_category)) => category = _category,
+            _ => {
+                let res = "That isn't a category.".to_string();
+                util::bad(ctx, msg).await;
+                util::respond(ctx, msg, &res).await;
+                return Ok(());
+            }
+        },
+        Err(_) => {
+            let res = "Please provide the ID of a category.".to_string();
+            util::bad(ctx, msg).await;
+            util::respond(ctx, msg, &res).await;
+            return Ok(());
+        }
+    }
+
+    let pattern;
+    if args.is_empty() {
+        pattern = None;
+    } else {
+        pattern = Some(args.rest().trim().to_string());
+    }
+
+    let channels: Vec<GuildChannel>;
+    match guild_id.channels(ctx).await {
+        Ok(_channels) => channels = _channels.into_iter().map(|(_, c)| c).collect(),
+        Err(why) => {
+            warn!("Failed to get the channels of {} because\n{}", guild_id, why);
+            util::warn(ctx, msg).await;
+            return Ok(());
+        }
+    }
+
+    let paired = rooms::link_category(&mut serving, &category, pattern, &channels);
+
+    if let Err(why) = store.put(&serving) {
+        warn!("Failed to save the rooms of {} because\n{}", serving.guild_id, why);
+        let res = format!("Failed to save the category, {}", why);
+        util::bad(ctx, msg).await;
+        util::respond(ctx, msg, &res).await;
+        return Ok(());
+    }
+
+    let res = format!("Linked {} with {} rooms so far.", category.name, paired);
+    util::respond(ctx, msg, &res).await;
+    util::good(ctx, msg).await;
+    Ok(())
+}
+
+#[command]
+// mode changes how a room lets people into its text-channels. With "overwrites" everyone in the
+// voice channels gets their own overwrite on the text-channels. With "role" they're given a role
+// made for the room instead, and only the role has an overwrite. That scales better on busy rooms
+// and keeps the audit log quiet.
+// args = [#text-channel or voice channel ID, overwrites or role]
+async fn mode(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let store = util::store(ctx).await;
+    let mut serving;
+    match store.find(guild_id) {
+        Some(_serving) => serving = _serving,
+        None => {
+            util::respond(ctx, msg, &"This server has no rooms".to_string()).await;
+            util::bad(ctx, msg).await;
+            return Ok(());
+        }
+    }
+
+    let channel_id = args.single::<String>().ok().and_then(|arg| util::parse_channel_id(&arg));
+    let mut room;
+    match channel_id.and_then(|id| rooms::find_room(&serving, id)) {
+        Some(_room) => room = _room.clone(),
+        None => {
+            let res = "Please mention a text channel or ID of a voice channel in a room.";
+            util::bad(ctx, msg).await;
+            util::respond(ctx, msg, &res.to_string()).await;
+            return Ok(());
+        }
+    }
+
+    let mode = args.rest().trim().to_lowercase();
+    match (mode.as_str(), room.access.clone()) {
+        ("role", Access::Overwrites) => {
+            let (voices, texts) = util::get_channels(ctx, &room).await;
+            let name = match voices.first() {
+                Some(voice) => format!("In {}", voice.name),
+                None => "In a room".to_string(),
+            };
+
+            let mut managed;
+            match store.managed(room.id) {
+                Ok(_managed) => managed = _managed,
+                Err(why) => {
+                    warn!("Failed to get the overwrites of room {} because\n{}", room.id, why);
+                    let res = format!("Failed to load the room, {}", why);
+                    util::bad(ctx, msg).await;
+                    util::respond(ctx, msg, &res).await;
+                    return Ok(());
+                }
+            }
+
+            let role;
+            // The role only lets people into the room, it doesn't grant anything itself.
+            let created = guild_id
+                .create_role(ctx, |r| r.name(&name).permissions(Permissions::empty()))
+                .await;
+            match created {
+                Ok(_role) => role = _role,
+                Err(why) => {
+                    warn!("Failed to create a role in {} because\n{}", guild_id, why);
+                    util::warn(ctx, msg).await;
+                    return Ok(());
+                }
+            }
+
+            // Everyone's own overwrite is replaced by the role, the ones staff made are kept.
+            for text in texts.iter() {
+                for perm in text.permission_overwrites.iter() {
+                    if let PermissionOverwriteType::Member(user_id) = perm.kind {
+                        if managed.contains(&(text.id, user_id))
+                            && util::revoke_access(ctx, text, user_id).await
+                        {
+                            managed.remove(&(text.id, user_id));
+                        }
+                    }
+                }
+            }
+            if let Err(why) = store.put_managed(room.id, &managed) {
+                warn!("Failed to save the overwrites of room {} because\n{}", room.id, why);
+            }
+            room.access = Access::Role { role_id: role.id };
+        }
+        ("overwrites", Access::Role { role_id }) => {
+            // Deleting the role also deletes its overwrites
+            if let Err(why) = guild_id.delete_role(ctx, role_id).await {
+                warn!("Failed to delete the role {} because\n{}", role_id, why);
+            }
+            util::role_holders(ctx).await.lock().unwrap().forget(role_id);
+            room.access = Access::Overwrites;
+        }
+        ("role", _) | ("overwrites", _) => {
+            util::respond(ctx, msg, &"The room already uses that mode.".to_string()).await;
+            util::good(ctx, msg).await;
+            return Ok(());
+        }
+        _ => {
+            let res = "Please pick either overwrites or role.".to_string();
+            util::bad(ctx, msg).await;
+            util::respond(ctx, msg, &res).await;
+            return Ok(());
+        }
+    }
+
+    rooms::replace_room(&mut serving, &room);
+    if let Err(why) = store.put(&serving) {
+        warn!("Failed to save the rooms of {} because\n{}", serving.guild_id, why);
+        let res = format!("Failed to save the room, {}", why);
+        util::bad(ctx, msg).await;
+        util::respond(ctx, msg, &res).await;
+        return Ok(());
+    }
+
+    // Let everyone in with the new mode right away
+    scheduler::schedule(ctx, guild_id, room.id).await;
+    util::good(ctx, msg).await;
+    Ok(())
+}
+
+#[command]
+// permissions changes what the people in a room are allowed and denied in its text-channels on
+// top of reading them. Permission names after "allow" are allowed and after "deny" are denied,
+// "reset" goes back to only reading. Without any permission names the current ones are shown.
+// args = [#text-channel or voice channel ID, allow, names..., deny, names...] or
+// [#text-channel or voice channel ID, reset]
+async fn permissions(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let store = util::store(ctx).await;
+    let mut serving;
+    match store.find(guild_id) {
+        Some(_serving) => serving = _serving,
+        None => {
+            util::respond(ctx, msg, &"This server has no rooms".to_string()).await;
+            util::bad(ctx, msg).await;
+            return Ok(());
+        }
+    }
+
+    let channel_id = args.single::<String>().ok().and_then(|arg| util::parse_channel_id(&arg));
+    let mut room;
+    match channel_id.and_then(|id| rooms::find_room(&serving, id)) {
+        Some(_room) => room = _room.clone(),
+        None => {
+            let res = "Please mention a text channel or ID of a voice channel in a room.";
+            util::bad(ctx, msg).await;
+            util::respond(ctx, msg, &res.to_string()).await;
+            return Ok(());
+        }
+    }
+
+    // Show the current permissions
+    if args.is_empty() {
+        let template = &room.permissions;
+        let res = format!(
+            "Allowed: {}\nDenied: {}\nAvailable: {}",
+            rooms::permission_names(template.allow).join(", "),
+            rooms::permission_names(template.deny).join(", "),
+            rooms::PERMISSIONS.iter().map(|(name, _)| *name).collect::<Vec<&str>>().join(", "),
+        );
+        util::respond(ctx, msg, &res).await;
+        util::good(ctx, msg).await;
+        return Ok(());
+    }
+
+    let mut template = PermissionTemplate::default();
+    // Whether the permission names being read are allowed or denied
+    let mut allowing = true;
+
+    for _arg in args.iter::<String>() {
+        let arg = _arg.unwrap_or_default();
+        match arg.to_lowercase().as_str() {
+            "reset" => template = PermissionTemplate::default(),
+            "allow" => allowing = true,
+            "deny" => allowing = false,
+            name => match rooms::parse_permission(name) {
+                Some(perm) if allowing => template.allow |= perm,
+                Some(perm) => template.deny |= perm,
+                None => {
+                    let res = format!("{} isn't a permission I know of.", arg);
+                    util::bad(ctx, msg).await;
+                    util::respond(ctx, msg, &res).await;
+                    return Ok(());
+                }
+            },
+        }
+    }
+
+    room.permissions = template;
+    rooms::replace_room(&mut serving, &room);
+    if let Err(why) = store.put(&serving) {
+        warn!("Failed to save the rooms of {} because\n{}", serving.guild_id, why);
+        let res = format!("Failed to save the room, {}", why);
+        util::bad(ctx, msg).await;
+        util::respond(ctx, msg, &res).await;
+        return Ok(());
+    }
+
+    // Give everyone the new permissions right away
+    scheduler::schedule(ctx, serving.guild_id, room.id).await;
+    util::good(ctx, msg).await;
+    Ok(())
+}
+
+#[command]
+// allowlist manages the users and roles that can always see a room's text-channels, even when
+// they're not in its voice channels. Without add or remove the allowlist is shown.
+// args = [#text-channel or voice channel ID, add or remove, @user, @role or ID...]
+async fn allowlist(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    manage_list(ctx, msg, args, false).await
+}
+
+#[command]
+// denylist manages the users and roles that are kept out of a room's text-channels, even when
+// they're in its voice channels, with an overwrite denying them. Taking them off the denylist
+// deletes it. Without add or remove the denylist is shown.
+// args = [#text-channel or voice channel ID, add or remove, @user, @role or ID...]
+async fn denylist(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    manage_list(ctx, msg, args, true).await
+}
+
+// manage_list is what the allowlist and denylist commands have in common, denying picks which of
+// the two lists of the room is managed.
+async fn manage_list(
+    ctx: &Context,
+    msg: &Message,
+    mut args: Args,
+    denying: bool,
+) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let store = util::store(ctx).await;
+    let mut serving;
+    match store.find(guild_id) {
+        Some(_serving) => serving = _serving,
+        None => {
+            util::respond(ctx, msg, &"This server has no rooms".to_string()).await;
+            util::bad(ctx, msg).await;
+            return Ok(());
+        }
+    }
+
+    let channel_id = args.single::<String>().ok().and_then(|arg| util::parse_channel_id(&arg));
+    let mut room;
+    match channel_id.and_then(|id| rooms::find_room(&serving, id)) {
+        Some(_room) => room = _room.clone(),
+        None => {
+            let res = "Please mention a text channel or ID of a voice channel in a room.";
+            util::bad(ctx, msg).await;
+            util::respond(ctx, msg, &res.to_string()).await;
+            return Ok(());
+        }
+    }
+
+    let name = if denying { "denylist" } else { "allowlist" };
+
+    // Show the current list
+    if args.is_empty() {
+        let list = if denying { &room.denylist } else { &room.allowlist };
+        let names = rooms::list_names(list);
+        let res = if names.is_empty() {
+            format!("No one is on the {} of this room.", name)
+        } else {
+            format!("On the {}: {}", name, names.join(", "))
+        };
+        util::respond(ctx, msg, &res).await;
+        util::good(ctx, msg).await;
+        return Ok(());
+    }
+
+    let adding;
+    match args.single::<String>().unwrap_or_default().to_lowercase().as_str() {
+        "add" => adding = true,
+        "remove" => adding = false,
+        _ => {
+            let res = "Please pick either add or remove.".to_string();
+            util::bad(ctx, msg).await;
+            util::respond(ctx, msg, &res).await;
+            return Ok(());
+        }
+    }
+
+    let mut targets = Vec::new();
+    for _arg in args.iter::<String>() {
+        let arg = _arg.unwrap_or_default();
+        match util::parse_target(ctx, guild_id, &arg).await {
+            Some(target) => targets.push(target),
+            None => {
+                let res = format!("{} isn't a user or role.", arg);
+                util::bad(ctx, msg).await;
+                util::respond(ctx, msg, &res).await;
+                return Ok(());
+            }
+        }
+    }
+
+    // Someone can't be on both lists, adding them to one takes them off the other.
+    let (list, other) = if denying {
+        (&mut room.denylist, &mut room.allowlist)
+    } else {
+        (&mut room.allowlist, &mut room.denylist)
+    };
+    // Everyone taken off the denylist and roles taken off the allowlist lose their overwrite,
+    // users taken off the allowlist lose theirs once synced. The overwrites of those moved onto
+    // the denylist are replaced by a deny once synced.
+    let mut lifted = Vec::new();
+    for target in targets {
+        let removed = if adding {
+            rooms::list_add(list, target);
+            rooms::list_remove(other, target)
+        } else {
+            rooms::list_remove(list, target)
+        };
+        let undenied = adding != denying;
+        if removed && (undenied || (!adding && matches!(target, rooms::Target::Role(_)))) {
+            lifted.push(target);
+        }
+    }
+
+    rooms::replace_room(&mut serving, &room);
+    if let Err(why) = store.put(&serving) {
+        warn!("Failed to save the rooms of {} because\n{}", serving.guild_id, why);
+        let res = format!("Failed to save the room, {}", why);
+        util::bad(ctx, msg).await;
+        util::respond(ctx, msg, &res).await;
+        return Ok(());
+    }
+
+    // Only the overwrites the bot made are removed, the deny or a role's overwrite made with the
+    // room's template. The ones staff made by hand are left alone.
+    let (_, texts) = util::get_channels(ctx, &room).await;
+    for target in lifted {
+        let is_role = matches!(target, rooms::Target::Role(_));
+        for text in texts.iter() {
+            let made = text.permission_overwrites.iter().any(|perm| {
+                planner::is_deny(perm, target)
+                    || (is_role && perm.kind == target.kind() && room.permissions.matches(perm))
+            });
+            if !made {
+                continue;
+            }
+            if let Err(why) = text.delete_permission(ctx, target.kind()).await {
+                warn!("Failed to remove {} from #{} because\n{}", target, text.name, why);
+            }
+        }
+    }
+
+    scheduler::schedule(ctx, serving.guild_id, room.id).await;
+    util::good(ctx, msg).await;
+    Ok(())
+}
+
+#[command]
+// grace sets how many seconds people keep access to a room's text-channels after leaving its voice
+// channels, so a reconnect doesn't cost them the chat. Auto-rooms and temporary rooms are only
+// torn down once it's over too. Without any args the grace period is shown, 0 turns it off.
+// args = [seconds]
+async fn grace(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let store = util::store(ctx).await;
+    let mut serving;
+    match util::serving(ctx, msg, store.as_ref()).await {
+        Some(_serving) => serving = _serving,
+        None => return Ok(()),
+    }
+
+    if args.is_empty() {
+        let res = format!("The grace period is {} seconds", serving.grace_period);
+        util::respond(ctx, msg, &res).await;
+        util::good(ctx, msg).await;
+        return Ok(());
+    }
+
+    match args.single::<u64>() {
+        Ok(seconds) => serving.grace_period = seconds,
+        Err(_) => {
+            let res = "Please give the grace period in seconds.".to_string();
+            util::bad(ctx, msg).await;
+            util::respond(ctx, msg, &res).await;
+            return Ok(());
+        }
+    }
+
+    if let Err(why) = store.put(&serving) {
+        warn!("Failed to save the rooms of {} because\n{}", serving.guild_id, why);
+        let res = format!("Failed to save the grace period, {}", why);
+        util::bad(ctx, msg).await;
+        util::respond(ctx, msg, &res).await;
+        return Ok(());
+    }
+
+    let res = format!("The grace period is now {} seconds", serving.grace_period);
+    util::respond(ctx, msg, &res).await;
+    util::good(ctx, msg).await;
+    Ok(())
+}
+
+#[command]
+// prefix changes the command prefix of a guild. Without any args the guild goes back to the prefix
+// in the config. Mentioning the bot works as a prefix no matter what.
+// args = [prefix]
+async fn prefix(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let store = util::store(ctx).await;
+    let mut serving;
+    match util::serving(ctx, msg, store.as_ref()).await {
+        Some(_serving) => serving = _serving,
+        None => return Ok(()),
+    }
+
+    let res;
+    if args.is_empty() {
+        let data = ctx.data.read().await;
+        let config = data.get::<Config>().unwrap();
+        serving.prefix = None;
+        res = format!("The prefix is back to `{}`", config.prefix);
+    } else {
+        let prefix = args.rest().trim().to_string();
+        res = format!("The prefix is now `{}`", prefix);
+        serving.prefix = Some(prefix);
+    }
+
+    if let Err(why) = store.put(&serving) {
+        warn!("Failed to save the rooms of {} because\n{}", serving.guild_id, why);
+        let res = format!("Failed to save the prefix, {}", why);
+        util::bad(ctx, msg).await;
+        util::respond(ctx, msg, &res).await;
+        return Ok(());
+    }
+
+    util::respond(ctx, msg, &res).await;
+    util::good(ctx, msg).await;
+    Ok(())
+}
+
+#[command]
+// cleanup deletes the deny-only overwrites older versions of the bot left on the text-channels of
+// this guild's rooms whenever someone left a voice channel. A moderator's block looks the same, so
+// they're only listed unless "confirm" is given.
+// args = [confirm]
+async fn cleanup(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let serving;
+    match util::store(ctx).await.find(msg.guild_id.unwrap()) {
+        Some(_serving) => serving = _serving,
+        None => {
+            util::respond(ctx, msg, &"This server has no rooms".to_string()).await;
+            util::good(ctx, msg).await;
+            return Ok(());
+        }
+    }
+
+    let confirmed = args.rest().trim().to_lowercase() == "confirm";
+    let mut found = 0;
+    let mut purged = 0;
+    let mut listed = String::new();
+    for room in serving.rooms.iter() {
+        for text_id in room.text_ids.iter() {
+            if let Ok(Channel::Guild(text)) = text_id.to_channel(ctx).await {
+                let stale = util::stale_overwrites(&text, &room.denylist);
+                if stale.is_empty() {
+                    continue;
+                }
+
+                found += stale.len();
+                listed += &format!("\n * <#{}>: {}", text.id, util::mentions(&stale));
+                if confirmed {
+                    purged += util::purge_stale_overwrites(ctx, &text, &stale).await;
+                }
+            }
+        }
+    }
+
+    let res = if found == 0 {
+        "There are no stale overwrites.".to_string()
+    } else if confirmed {
+        format!("Deleted {} stale overwrites of{}", purged, listed)
+    } else {
+        format!(
+            "These overwrites only deny reading the text-channel. Check that none of them were \
+             made by a moderator, then run cleanup confirm to delete them.{}",
+            listed
+        )
+    };
+    util::respond(ctx, msg, &res).await;
+    util::good(ctx, msg).await;
+    Ok(())
+}
+
+#[command]
+// sync reviews a room right away, or every room of the guild with "all", and replies with who was
+// granted and revoked access. Handy when someone edited the overwrites by hand. Auto-rooms get
+// their text-channel made or torn down like on any review. With --dry-run, or in dry-run mode,
+// nothing is changed and what would've changed is reported instead.
+// args = [#text-channel, voice channel ID or all, --dry-run]
+async fn sync(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let serving;
+    match util::store(ctx).await.find(guild_id) {
+        Some(_serving) => serving = _serving,
+        None => {
+            util::respond(ctx, msg, &"This server has no rooms".to_string()).await;
+            util::bad(ctx, msg).await;
+            return Ok(());
+        }
+    }
+
+    let arg = args.single::<String>().unwrap_or_default();
+    let to_sync: Vec<Room>;
+    if arg.to_lowercase() == "all" {
+        to_sync = serving.rooms.clone();
+    } else {
+        match util::parse_channel_id(&arg).and_then(|id| rooms::find_room(&serving, id)) {
+            Some(room) => to_sync = vec![room.clone()],
+            None => {
+                let res = "Please mention a text channel or ID of a voice channel, or all.";
+                util::bad(ctx, msg).await;
+                util::respond(ctx, msg, &res.to_string()).await;
+                return Ok(());
+            }
+        }
+    }
+
+    let rehearse = args.rest().trim() == "--dry-run";
+    let mut granted = Vec::<UserId>::new();
+    let mut revoked = Vec::<UserId>::new();
+    let mut actions = Vec::<String>::new();
+    // Guilds in dry-run mode get a dry run even without --dry-run.
+    let mut dry_run = rehearse;
+    for room in to_sync.iter() {
+        let summary = scheduler::review(ctx, guild_id, room.id, rehearse).await;
+        dry_run |= summary.dry_run;
+        // Someone in several of the rooms is only listed once.
+        for user_id in summary.granted {
+            if !granted.contains(&user_id) {
+                granted.push(user_id);
+            }
+        }
+        for user_id in summary.revoked {
+            if !revoked.contains(&user_id) {
+                revoked.push(user_id);
+            }
+        }
+        actions.extend(summary.actions);
+    }
+
+    let rooms = if to_sync.len() == 1 { "room" } else { "rooms" };
+    let mut res = if dry_run {
+        format!(
+            "Syncing {} {} would\n * Grant: {}\n * Revoke: {}",
+            to_sync.len(),
+            rooms,
+            util::mentions(&granted),
+            util::mentions(&revoked),
+        )
+    } else {
+        format!(
+            "Synced {} {}\n * Granted: {}\n * Revoked: {}",
+            to_sync.len(),
+            rooms,
+            util::mentions(&granted),
+            util::mentions(&revoked),
+        )
+    };
+    for action in actions {
+        res += &format!("\n * {}", action);
+    }
+    util::respond(ctx, msg, &res).await;
+    util::good(ctx, msg).await;
+    Ok(())
+}
+
+#[command]
+// adopt makes the bot manage the overwrites on a room's text-channels that only allow reading them,
+// or on every room's with "all", so they're revoked like its own once their member leaves. Older
+// versions of the bot didn't keep track of the overwrites they made, but staff might've made the
+// same by hand, so an admin has to adopt them. Who was adopted is replied with and logged.
+// args = [#text-channel, voice channel ID or all]
+async fn adopt(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let store = util::store(ctx).await;
+    let serving;
+    match store.find(guild_id) {
+        Some(_serving) => serving = _serving,
+        None => {
+            util::respond(ctx, msg, &"This server has no rooms".to_string()).await;
+            util::bad(ctx, msg).await;
+            return Ok(());
+        }
+    }
+
+    let arg = args.single::<String>().unwrap_or_default();
+    let to_adopt: Vec<Room>;
+    if arg.to_lowercase() == "all" {
+        to_adopt = serving.rooms.clone();
+    } else {
+        match util::parse_channel_id(&arg).and_then(|id| rooms::find_room(&serving, id)) {
+            Some(room) => to_adopt = vec![room.clone()],
+            None => {
+                let res = "Please mention a text channel or ID of a voice channel, or all.";
+                util::bad(ctx, msg).await;
+                util::respond(ctx, msg, &res.to_string()).await;
+                return Ok(());
+            }
+        }
+    }
+
+    let mut res = String::new();
+    // Rooms in role access mode only have the role's overwrite.
+    for room in to_adopt.iter().filter(|room| room.access == Access::Overwrites) {
+        let mut managed;
+        match store.managed(room.id) {
+            Ok(_managed) => managed = _managed,
+            Err(why) => {
+                warn!("Failed to get the overwrites of room {} because\n{}", room.id, why);
+                let res = format!("Failed to load the room, {}", why);
+                util::bad(ctx, msg).await;
+                util::respond(ctx, msg, &res).await;
+                return Ok(());
+            }
+        }
+
+        let (_, texts) = util::get_channels(ctx, room).await;
+        let adopted: Vec<(ChannelId, UserId)> = core::adopt_overwrites(&texts)
+            .difference(&managed)
+            .copied()
+            .collect();
+        if adopted.is_empty() {
+            continue;
+        }
+
+        managed.extend(adopted.iter().copied());
+        if let Err(why) = store.put_managed(room.id, &managed) {
+            warn!("Failed to save the overwrites of room {} because\n{}", room.id, why);
+            let res = format!("Failed to save the room, {}", why);
+            util::bad(ctx, msg).await;
+            util::respond(ctx, msg, &res).await;
+            return Ok(());
+        }
+
+        for text in texts.iter() {
+            let users: Vec<UserId> = adopted
+                .iter()
+                .filter(|(text_id, _)| *text_id == text.id)
+                .map(|(_, user_id)| *user_id)
+                .collect();
+            if users.is_empty() {
+                continue;
+            }
+
+            let ids: Vec<String> = users.iter().map(|id| id.to_string()).collect();
+            info!(
+                "Adopted the overwrites of #{} in {} for {}",
+                text.name,
+                guild_id,
+                ids.join(", ")
+            );
+            res += &format!("\n * <#{}>: {}", text.id, util::mentions(&users));
+        }
+    }
+
+    if res.is_empty() {
+        res = "There were no overwrites to adopt.".to_string();
+    } else {
+        res = format!("Adopted the overwrites of{}", res);
+    }
+    util::respond(ctx, msg, &res).await;
+    util::good(ctx, msg).await;
+    Ok(())
+}
+
+#[command]
+// dryrun turns dry-run mode on or off for a guild. In dry-run mode reviewing rooms only reports
+// what it would change, in the log and in the given channel. That includes making and deleting the
+// channels of auto-rooms and hubs.
+// args = [on or off, #channel]
+async fn dryrun(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let store = util::store(ctx).await;
+    let mut serving;
+    match util::serving(ctx, msg, store.as_ref()).await {
+        Some(_serving) => serving = _serving,
+        None => return Ok(()),
+    }
+
+    let res;
+    match args.single::<String>().unwrap_or_default().to_lowercase().as_str() {
+        "on" => {
+            let channel = args.single::<String>().ok();
+            let channel_id = channel.and_then(|arg| util::parse_channel_id(&arg));
+            serving.dry_run = true;
+            serving.report_channel = channel_id;
+            res = match serving.report_channel {
+                Some(channel_id) => format!("Dry runs will be reported in <#{}>", channel_id),
+                None => "Dry runs will be reported in the log".to_string(),
+            };
+        }
+        "off" => {
+            serving.dry_run = false;
+            serving.report_channel = None;
+            res = "Rooms are synced for real again".to_string();
+        }
+        _ => {
+            let res = "Please pick either on or off.".to_string();
+            util::bad(ctx, msg).await;
+            util::respond(ctx, msg, &res).await;
+            return Ok(());
+        }
+    }
+
+    if let Err(why) = store.put(&serving) {
+        warn!("Failed to save the rooms of {} because\n{}", serving.guild_id, why);
+        let res = format!("Failed to save dry-run mode, {}", why);
+        util::bad(ctx, msg).await;
+        util::respond(ctx, msg, &res).await;
+        return Ok(());
+    }
+
+    util::respond(ctx, msg, &res).await;
+    util::good(ctx, msg).await;
+    Ok(())
+}
+
+#[command]
+// stats shows how often syncing found what it needed in the cache rather than fetching it over
+// HTTP, since the bot started.
+async fn stats(ctx: &Context, msg: &Message) -> CommandResult {
+    let res = format!("Cache lookups since starting\n{}", util::metrics(ctx).await);
+    util::respond(ctx, msg, &res).await;
+    util::good(ctx, msg).await;
+    Ok(())
+}
+
 #[command]
 // list will send a message with all the channels that are linked with each other.
 // output example:
 // Linked Channels:
 // - <#text-channel ID> -> voice channel name
-// - <#text-channel ID> -> voice channel name
+// - <#text-channel ID>, <#text-channel ID> -> voice channel name
+// - <#text-channel ID> -> voice channel name, voice channel name
 async fn list(ctx: &Context, msg: &Message) -> CommandResult {
-    let data = ctx.data.read().await;
-    let config = data.get::<Config>().unwrap();
     let serving;
 
     // Get the rooms for this guild
-    if let Some(_s) = config.serving.get(msg.guild_id.unwrap().as_u64()) {
+    if let Some(_s) = util::store(ctx).await.find(msg.guild_id.unwrap()) {
         serving = _s;
     } else {
         // if they don't have any rooms then tell them there are no channels linked.
@@ -304,44 +1181,95 @@ async fn list(ctx: &Context, msg: &Message) -> CommandResult {
         return Ok(());
     }
 
+    let list = describe(ctx, &serving).await;
+    util::respond(&ctx, &msg, &list).await;
+    util::good(ctx, msg).await;
+    Ok(())
+}
+
+// describe lists the categories, hubs and rooms of a guild the way the list command shows them.
+pub async fn describe(ctx: &Context, serving: &Serving) -> String {
     // This is what's responded
-    let mut list = String::from("Linked Channels:\n");
+    let mut list = String::new();
+
+    // List the linked categories first
+    if !serving.categories.is_empty() {
+        list.push_str("Linked Categories:\n");
+        for link in serving.categories.iter() {
+            let mut list_item = format!(" - {}", link.category_id);
+            if let Ok(Channel::Category(category)) = link.category_id.to_channel(ctx).await {
+                list_item = format!(" - {}", category.name);
+            }
+            if let Some(pattern) = &link.pattern {
+                list_item += &format!(" ({})", pattern);
+            }
+            list.push_str(&format!("{}\n", list_item));
+        }
+    }
+
+    // Then the hubs
+    if !serving.hubs.is_empty() {
+        list.push_str("Hubs:\n");
+        for hub_id in serving.hubs.iter() {
+            match hub_id.to_channel(ctx).await {
+                Ok(Channel::Guild(hub)) => list.push_str(&format!(" - {}\n", hub.name)),
+                _ => list.push_str(&format!(" - {}\n", hub_id)),
+            }
+        }
+    }
+
+    list.push_str("Linked Channels:\n");
 
     // Iterate through all the rooms and list them
     for room in serving.rooms.iter() {
-        let mut list_item: String;
-        match room.text_id.to_channel(ctx).await {
-            Ok(text) => {
-                if let Some(_) = text.guild() {
-                    list_item = format!(" - <#{}> -> ", &room.text_id);
-                } else {
-                    list_item = format!(" - {} -> ", room.text_id);
+        let mut texts = Vec::<String>::new();
+        for text_id in room.text_ids.iter() {
+            match text_id.to_channel(ctx).await {
+                Ok(text) => {
+                    if let Some(_) = text.guild() {
+                        texts.push(format!("<#{}>", text_id));
+                    } else {
+                        texts.push(text_id.to_string());
+                    }
+                }
+                Err(_) => {
+                    texts.push(text_id.to_string());
                 }
-            }
-            Err(_) => {
-                list_item = format!(" - {} -> ", room.text_id);
             }
         }
 
-        match room.voice_id.to_channel(ctx).await {
-            Ok(voice) => {
-                let name_or_id;
-                if let Some(guild_chan) = voice.guild() {
-                    name_or_id = guild_chan.name;
-                } else {
-                    name_or_id = room.voice_id.to_string();
+        let mut voices = Vec::<String>::new();
+        for voice_id in room.voice_ids.iter() {
+            match voice_id.to_channel(ctx).await {
+                Ok(voice) => {
+                    if let Some(guild_chan) = voice.guild() {
+                        voices.push(guild_chan.name);
+                    } else {
+                        voices.push(voice_id.to_string());
+                    }
+                }
+                Err(_) => {
+                    voices.push(voice_id.to_string());
                 }
-                list_item += &format!("{}\n", name_or_id).to_string();
-            }
-            Err(_) => {
-                list_item += &format!("{}\n", &room.voice_id).to_string();
             }
         }
 
-        list.push_str(list_item.as_str());
+        // Auto-rooms might not have a text-channel right now
+        match room.kind {
+            RoomKind::Auto { .. } => texts.push("(auto)".to_string()),
+            RoomKind::Temporary { owner } => texts.push(format!("(owned by <@{}>)", owner)),
+            RoomKind::Linked => {}
+        }
+
+        let mut list_item = format!(" - {} -> {}", texts.join(", "), voices.join(", "));
+        if !room.allowlist.is_empty() {
+            list_item += &format!(" (allowed: {})", rooms::list_names(&room.allowlist).join(", "));
+        }
+        if !room.denylist.is_empty() {
+            list_item += &format!(" (denied: {})", rooms::list_names(&room.denylist).join(", "));
+        }
+        list.push_str(&format!("{}\n", list_item));
     }
 
-    util::respond(&ctx, &msg, &list).await;
-    util::good(ctx, msg).await;
-    Ok(())
+    list
 }
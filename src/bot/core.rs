@@ -1,93 +1,1015 @@
-use crate::bot::util::{get_channels, grant_access, revoke_access};
-use crate::config::{Room, Serving};
-use log::info;
+use crate::bot::planner::{self, Occupant, Plan, Policy};
+use crate::bot::rooms::{self, DEFAULT_PATTERN};
+use crate::bot::scheduler;
+use crate::bot::util::{self, get_channels, grant_access, revoke_access};
+use crate::bot::util::{grant_role, grant_role_access, revoke_role};
+use crate::config::{Access, MemberList, PermissionTemplate, Room, RoomKind, Serving};
+use crate::store::Managed;
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{self, StreamExt};
+use log::{info, warn};
 use serenity::client::Context;
 use serenity::model::prelude::*;
+use serenity::prelude::TypeMapKey;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::delay_for;
+
+// How many rooms reconcile will sync at the same time.
+const RECONCILE_CONCURRENCY: usize = 4;
+// How many members Discord gives at once when fetching the members of a guild.
+const MEMBERS_PER_PAGE: u64 = 1000;
+
+// Departures is when members left a room and when rooms emptied, by the room's ID. It's how their
+// grace period survives between syncs.
+pub struct Departures;
+
+impl TypeMapKey for Departures {
+    type Value = Arc<Mutex<Departed>>;
+}
+
+#[derive(Debug, Default)]
+pub struct Departed {
+    members: HashMap<(u64, UserId), Instant>,
+    rooms: HashMap<u64, Instant>,
+    // The rooms that'll be synced again once a grace period is over, only one resync is kept
+    // waiting per room.
+    resyncs: HashSet<u64>,
+}
+
+// SyncSummary is what reviewing a room did to it.
+#[derive(Debug, Default, Clone)]
+pub struct SyncSummary {
+    pub granted: Vec<UserId>,
+    pub revoked: Vec<UserId>,
+    // Anything else that was done to the room, like making or deleting its channels. Only dry runs
+    // fill it in.
+    pub actions: Vec<String>,
+    // Nothing was changed, the rest is what would've been.
+    pub dry_run: bool,
+}
+
+impl SyncSummary {
+    // would is the summary of a dry run that would only have done action.
+    fn would(action: String) -> SyncSummary {
+        SyncSummary {
+            actions: vec![action],
+            dry_run: true,
+            ..SyncSummary::default()
+        }
+    }
+}
+
+// reconcile syncs every room of every guild being served. It's ran once the cache is ready so
+// anyone that joined or left a voice channel while the bot was offline gets the right access.
+// Rooms are reviewed through the scheduler so a voice state update can't review them at the same
+// time.
+pub async fn reconcile(ctx: &Context, serving: Vec<Serving>) {
+    let rooms: Vec<(GuildId, u64)> = serving
+        .iter()
+        .flat_map(|s| s.rooms.iter().map(move |room| (s.guild_id, room.id)))
+        .collect();
+    let total = rooms.len();
+
+    let summaries: Vec<SyncSummary> = stream::iter(rooms)
+        .map(|(guild_id, room_id)| scheduler::review(ctx, guild_id, room_id, false))
+        .buffer_unordered(RECONCILE_CONCURRENCY)
+        .collect()
+        .await;
+
+    let granted: usize = summaries.iter().map(|s| s.granted.len()).sum();
+    let revoked: usize = summaries.iter().map(|s| s.revoked.len()).sum();
+    info!(
+        "
+Reconciled Rooms
+ * Rooms: {}
+ * Granted: {}
+ * Revoked: {}
+{}",
+        total,
+        granted,
+        revoked,
+        util::metrics(ctx).await,
+    );
+}
 
 // review_state reviews a member's voice state and checks if the voice channel they joined or left
-// needs synced by sync_rooms.
+// needs synced by sync_rooms. The review is left to the scheduler so bursts of joins and leaves
+// only sync the room once.
 pub async fn review_state(ctx: &Context, serving: &Serving, state: &VoiceState) {
     if let Some(channel_id) = state.channel_id {
         if let Some(room) = get_room(&serving, &channel_id) {
-            sync_room(&ctx, &room).await;
+            scheduler::schedule(ctx, serving.guild_id, room.id).await;
         }
     }
 }
 
-// sync_room is where all the magic happens. It will make sure the people in the voice channel can
-// see the linked text-channel. It also revokes access to the text-channel for the ones that aren't
-// in the voice-channel.
-async fn sync_room(ctx: &Context, room: &Room) {
-    let channels = get_channels(ctx, room).await;
-    let voice;
-    let text;
+// review_room syncs a room. Auto-rooms get their text-channel made before syncing once someone is
+// in the voice channel, and torn down once everyone left. Temporary rooms are deleted altogether
+// once everyone left. Rooms are only torn down once the guild's grace period is over, in case
+// everyone is only reconnecting. Rehearsing, or the guild being in dry-run mode, only works out
+// what the review would do.
+pub async fn review_room(
+    ctx: &Context,
+    guild_id: GuildId,
+    room: &Room,
+    rehearse: bool,
+) -> SyncSummary {
+    let (guild_dry_run, report_channel) = util::dry_run(ctx, guild_id).await;
+    let dry_run = rehearse || guild_dry_run;
+    let (voices, _) = get_channels(ctx, room).await;
+
+    let summary = settle_room(ctx, guild_id, room, &voices, dry_run).await;
+
+    // Guilds in dry-run mode get told what would've changed, rehearsals are reported by whoever
+    // asked for them.
+    if guild_dry_run && !rehearse {
+        let names: Vec<&str> = voices.iter().map(|voice| voice.name.as_str()).collect();
+        util::report_dry_run(ctx, report_channel, room.id, &names.join(", "), &summary).await;
+    }
+
+    summary
+}
+
+// settle_room makes or tears down the channels of a room for the people in it and syncs it, see
+// review_room.
+async fn settle_room(
+    ctx: &Context,
+    guild_id: GuildId,
+    room: &Room,
+    voices: &[GuildChannel],
+    dry_run: bool,
+) -> SyncSummary {
+    if room.kind == RoomKind::Linked {
+        return sync_room(ctx, room, dry_run, false).await;
+    }
+
+    let mut occupied;
+    match members_in_room(ctx, room, voices).await {
+        Some((members, unknown)) => occupied = !members.is_empty() || !unknown.is_empty(),
+        None => return SyncSummary::default(),
+    }
+
+    // Only a room that still has its text-channels was occupied until now and is kept around. An
+    // idle one without them isn't brought back just to wait out a grace period.
+    let live = !room.text_ids.is_empty();
+    let mut grace = Grace::new(ctx, guild_id, room).await;
+    if dry_run {
+        occupied = occupied || (live && grace.is_room_held());
+    } else if grace.hold_room(occupied, live) {
+        grace.resync(ctx, guild_id);
+        occupied = true;
+    }
+
+    let archive;
+    match room.kind {
+        RoomKind::Auto { archive: _archive } => archive = _archive,
+        RoomKind::Temporary { .. } => {
+            if occupied {
+                return sync_room(ctx, room, dry_run, false).await;
+            }
+            if dry_run {
+                return SyncSummary::would(format!("Would delete {}", channels(&room.voice_ids)));
+            }
+            delete_temporary_room(ctx, guild_id, room).await;
+            return SyncSummary::default();
+        }
+        RoomKind::Linked => return sync_room(ctx, room, dry_run, false).await,
+    }
+
+    let mut room = room.clone();
+
+    // Someone joined an auto-room without a text-channel. The one archived last time is brought
+    // back if it's still around.
+    if occupied && room.text_ids.is_empty() {
+        if dry_run {
+            return SyncSummary::would(match room.archived_ids.last() {
+                Some(text_id) => format!("Would bring back <#{}> from the archive", text_id),
+                None => format!("Would create a text-channel for {}", voices[0].name),
+            });
+        }
+
+        let text_id;
+        match unarchive(ctx, &mut room).await {
+            Some(_text_id) => text_id = _text_id,
+            None => match create_room_text(ctx, guild_id, &voices[0]).await {
+                Some(_text_id) => text_id = _text_id,
+                None => return SyncSummary::default(),
+            },
+        }
+        room.text_ids.push(text_id);
+        save_room(ctx, guild_id, &room).await;
+    }
 
-    match channels {
-        Some((_voice, _text)) => {
-            voice = _voice;
-            text = _text;
+    // Everyone left an auto-room with a text-channel. Archived text-channels are synced one last
+    // time so no one can see them anymore, and kept for the next time someone joins. The room's
+    // own grace period is over, so its last members don't get one of their own.
+    if !occupied && !room.text_ids.is_empty() {
+        let mut summary = SyncSummary::default();
+        if archive {
+            summary = sync_room(ctx, &room, dry_run, true).await;
         }
-        None => return,
+
+        if dry_run {
+            let texts = channels(&room.text_ids);
+            summary.dry_run = true;
+            if archive {
+                summary.actions.push(format!("Would archive {}", texts));
+            } else {
+                summary.actions.push(format!("Would delete {}", texts));
+            }
+            return summary;
+        }
+
+        let texts = std::mem::take(&mut room.text_ids);
+        if archive {
+            room.archived_ids.extend(texts.iter().copied());
+        }
+        save_room(ctx, guild_id, &room).await;
+
+        if !archive {
+            delete_channels(ctx, &texts).await;
+        }
+        return summary;
     }
 
-    info!("Syncing {} and #{}", voice.name, text.name);
-    let mut members_in_vc: Vec<Member>;
+    sync_room(ctx, &room, dry_run, false).await
+}
 
-    match voice.members(&ctx).await {
-        Ok(_members) => members_in_vc = _members,
+// channels mentions a list of channels.
+fn channels(ids: &[ChannelId]) -> String {
+    let mentions: Vec<String> = ids.iter().map(|id| format!("<#{}>", id)).collect();
+    mentions.join(", ")
+}
+
+// create_temporary_room makes a personal voice channel and text-channel for someone that joined a
+// hub, then moves them into it. The room is deleted once everyone left, see review_room.
+pub async fn create_temporary_room(
+    ctx: &Context,
+    guild_id: GuildId,
+    hub_id: ChannelId,
+    user_id: UserId,
+) {
+    let hub;
+    match hub_id.to_channel(ctx).await {
+        Ok(Channel::Guild(_hub)) => hub = _hub,
+        _ => return,
+    }
+
+    // Guilds in dry-run mode only get told about the room, they're left in the hub.
+    let (dry_run, report_channel) = util::dry_run(ctx, guild_id).await;
+    if dry_run {
+        let action = format!("Would create a room for <@{}> and move them into it", user_id);
+        let summary = SyncSummary::would(action);
+        let hub_key = *hub_id.as_u64();
+        util::report_dry_run(ctx, report_channel, hub_key, &hub.name, &summary).await;
+        return;
+    }
+
+    let name;
+    match user_id.to_user(ctx).await {
+        Ok(user) => name = format!("{}'s Room", user.name),
         Err(_) => return,
     }
 
-    for perm in &text.permission_overwrites {
-        match perm.kind {
-            PermissionOverwriteType::Member(user_id) => {
-                let (is_in_vc, i) = in_vc(user_id, &members_in_vc);
+    // The owner can rename their voice channel, set a user limit, etc.
+    let owner = PermissionOverwrite {
+        allow: Permissions::MANAGE_CHANNELS | Permissions::CONNECT,
+        deny: Permissions::empty(),
+        kind: PermissionOverwriteType::Member(user_id),
+    };
+
+    let created = guild_id
+        .create_channel(ctx, |c| {
+            c.name(&name);
+            c.kind(ChannelType::Voice);
+            c.permissions(vec![owner]);
+            if let Some(category_id) = hub.category_id {
+                c.category(category_id);
+            }
+            c
+        })
+        .await;
+
+    let voice;
+    match created {
+        Ok(_voice) => voice = _voice,
+        Err(why) => {
+            warn!("Failed to create a voice channel for {} because\n{}", user_id, why);
+            return;
+        }
+    }
+
+    let text_id;
+    match create_room_text(ctx, guild_id, &voice).await {
+        Some(_text_id) => text_id = _text_id,
+        None => {
+            delete_channels(ctx, &[voice.id]).await;
+            return;
+        }
+    }
+
+    let mut room = Room::new(vec![voice.id], vec![text_id]);
+    room.kind = RoomKind::Temporary { owner: user_id };
+
+    // The room is saved before they're moved so the move is reviewed like joining any other room.
+    let store = util::store(ctx).await;
+    let mut serving;
+    match store.get(guild_id) {
+        Ok(_serving) => serving = _serving.unwrap_or_else(|| Serving::new(guild_id)),
+        Err(why) => {
+            warn!("Failed to get the rooms of {} because\n{}", guild_id, why);
+            delete_channels(ctx, &room.voice_ids).await;
+            delete_channels(ctx, &room.text_ids).await;
+            return;
+        }
+    }
+    serving.rooms.push(room.clone());
+    if let Err(why) = store.put(&serving) {
+        warn!("Failed to save the rooms of {} because\n{}", guild_id, why);
+        delete_channels(ctx, &room.voice_ids).await;
+        delete_channels(ctx, &room.text_ids).await;
+        return;
+    }
+
+    if let Err(why) = guild_id.move_member(ctx, user_id, voice.id).await {
+        warn!("Failed to move {} into {} because\n{}", user_id, voice.name, why);
+        delete_temporary_room(ctx, guild_id, &room).await;
+    }
+}
+
+// delete_temporary_room removes a temporary room and deletes its channels.
+async fn delete_temporary_room(ctx: &Context, guild_id: GuildId, room: &Room) {
+    let store = util::store(ctx).await;
+    if let Some(mut serving) = store.find(guild_id) {
+        if rooms::remove_room(&mut serving, room) {
+            if let Err(why) = store.put(&serving) {
+                warn!("Failed to save the rooms of {} because\n{}", guild_id, why);
+                return;
+            }
+        }
+    }
+
+    delete_channels(ctx, &room.voice_ids).await;
+    delete_channels(ctx, &room.text_ids).await;
+    forget_rooms(ctx, guild_id, std::slice::from_ref(room)).await;
+}
+
+async fn delete_channels(ctx: &Context, ids: &[ChannelId]) {
+    for id in ids.iter() {
+        if let Err(why) = id.delete(ctx).await {
+            warn!("Failed to delete {} because\n{}", id, why);
+        }
+    }
+}
+
+// create_room_text makes a text-channel for a room next to its voice channel. No one but the bot
+// can see it until sync_room lets the people in the voice channel in.
+async fn create_room_text(
+    ctx: &Context,
+    guild_id: GuildId,
+    voice: &GuildChannel,
+) -> Option<ChannelId> {
+    let bot_id = ctx.cache.current_user_id().await;
+    let overwrites = vec![
+        PermissionOverwrite {
+            allow: Permissions::empty(),
+            deny: Permissions::READ_MESSAGES,
+            kind: PermissionOverwriteType::Role(RoleId(*guild_id.as_u64())),
+        },
+        PermissionOverwrite {
+            allow: Permissions::READ_MESSAGES | Permissions::MANAGE_ROLES,
+            deny: Permissions::empty(),
+            kind: PermissionOverwriteType::Member(bot_id),
+        },
+    ];
+    let name = rooms::text_name(DEFAULT_PATTERN, &voice.name);
+
+    let created = guild_id
+        .create_channel(ctx, |c| {
+            c.name(&name);
+            c.kind(ChannelType::Text);
+            c.permissions(overwrites);
+            if let Some(category_id) = voice.category_id {
+                c.category(category_id);
+            }
+            c
+        })
+        .await;
+
+    match created {
+        Ok(text) => {
+            info!("Created #{} for {}", text.name, voice.name);
+            Some(text.id)
+        }
+        Err(why) => {
+            warn!("Failed to create a text-channel for {} because\n{}", voice.name, why);
+            None
+        }
+    }
+}
+
+// unarchive takes the most recently archived text-channel of an auto-room that still exists out of
+// its archive. Archived text-channels that were deleted in the meantime are dropped.
+async fn unarchive(ctx: &Context, room: &mut Room) -> Option<ChannelId> {
+    while let Some(text_id) = room.archived_ids.pop() {
+        if let Ok(Channel::Guild(text)) = text_id.to_channel(ctx).await {
+            info!("Brought back #{} from the archive", text.name);
+            return Some(text_id);
+        }
+    }
+    None
+}
+
+// save_room saves the changes the bot made to a room.
+async fn save_room(ctx: &Context, guild_id: GuildId, room: &Room) {
+    let store = util::store(ctx).await;
+    if let Some(mut serving) = store.find(guild_id) {
+        if rooms::replace_room(&mut serving, room) {
+            if let Err(why) = store.put(&serving) {
+                warn!("Failed to save the rooms of {} because\n{}", guild_id, why);
+            }
+        }
+    }
+}
+
+// sync_room is where all the magic happens. It will make sure the people in the voice channels can
+// see the linked text-channels. It also revokes access to the text-channels for the ones that
+// aren't in any of the voice-channels. What has to change is worked out by the planner, sync_room
+// fetches what it needs and carries out the plan. In a dry run the plan is only summarized.
+// Closing syncs a room that's being torn down, everyone outside of it is revoked right away.
+async fn sync_room(ctx: &Context, room: &Room, dry_run: bool, closing: bool) -> SyncSummary {
+    let mut summary = SyncSummary::default();
+    let (voices, texts) = get_channels(ctx, room).await;
+    let mut members_in_vc;
+    let unknown;
+
+    match members_in_room(ctx, room, &voices).await {
+        Some((_members, _unknown)) => {
+            members_in_vc = _members;
+            unknown = _unknown;
+        }
+        None => return summary,
+    }
+
+    let guild_id;
+    match voices.first() {
+        Some(voice) => guild_id = voice.guild_id,
+        None => return summary,
+    }
+
+    summary.dry_run = dry_run;
+
+    let mut grace = Grace::new(ctx, guild_id, room).await;
+    if closing {
+        grace.close(dry_run);
+    }
+    if !dry_run {
+        let mut returned: Vec<UserId> = members_in_vc.iter().map(|m| m.user.id).collect();
+        returned.extend(unknown.iter().copied());
+        grace.returned(&returned);
+    }
+
+    // Everyone on the allowlist is treated like they're in the voice channels, the planner keeps
+    // everyone on the denylist out. Those whose member couldn't be fetched keep the overwrite or
+    // role they have rather than losing it over a failed lookup, but aren't given a new one.
+    add_allowed(ctx, guild_id, &room.allowlist, &mut members_in_vc).await;
+    let mut occupants: Vec<Occupant> = members_in_vc.iter().map(Occupant::from).collect();
+    occupants.extend(unknown.iter().map(|user_id| Occupant {
+        user_id: *user_id,
+        roles: Vec::new(),
+        known: false,
+    }));
+    let policy = Policy {
+        template: &room.permissions,
+        denylist: &room.denylist,
+    };
+
+    let names: Vec<&str> = voices.iter().map(|voice| voice.name.as_str()).collect();
+    for text in texts.iter() {
+        info!("Syncing {} and #{}", names.join(", "), text.name);
+        for role_id in room.allowlist.roles.iter() {
+            if !dry_run && !room.denylist.roles.contains(role_id) {
+                sync_role_overwrite(ctx, text, *role_id, &room.permissions).await;
+            }
+        }
+    }
+
+    match room.access {
+        Access::Overwrites => {
+            // Overwrites made before the bot kept track of them aren't managed until they're
+            // adopted with the adopt command, so they're never revoked by surprise.
+            let store = util::store(ctx).await;
+            let mut managed;
+            match store.managed(room.id) {
+                Ok(_managed) => managed = _managed,
+                Err(why) => {
+                    warn!("Failed to get the overwrites of room {} because\n{}", room.id, why);
+                    return summary;
+                }
+            }
+            let saved = managed.clone();
+
+            for text in texts.iter() {
+                let overwrites = &text.permission_overwrites;
+                let plan = planner::plan_text(text.id, overwrites, &occupants, &managed, &policy);
+                if dry_run {
+                    rehearse_plan(&format!("#{}", text.name), plan, &grace, &mut summary);
+                    continue;
+                }
+                execute_text(
+                    ctx,
+                    text,
+                    &room.permissions,
+                    plan,
+                    &mut managed,
+                    &mut grace,
+                    &mut summary,
+                )
+                .await;
+            }
+
+            // Text channels that were unlinked aren't the room's business anymore.
+            managed.retain(|(text_id, _)| room.text_ids.contains(text_id));
+            if !dry_run && managed != saved {
+                if let Err(why) = store.put_managed(room.id, &managed) {
+                    warn!("Failed to save the overwrites of room {} because\n{}", room.id, why);
+                }
+            }
+        }
+        Access::Role { role_id } => {
+            for text in texts.iter() {
+                if !dry_run {
+                    sync_role_overwrite(ctx, text, role_id, &room.permissions).await;
+                }
+            }
+
+            let holders = role_holders(ctx, guild_id, role_id).await;
+            let plan = planner::plan_role(role_id, &holders, &occupants, &policy);
+            if dry_run {
+                rehearse_plan(&format!("the role {}", role_id), plan, &grace, &mut summary);
+            } else {
+                execute_role(ctx, guild_id, role_id, plan, &mut grace, &mut summary).await;
+            }
+        }
+    }
+
+    // Everyone on the denylist gets an overwrite that keeps them out, so neither the room nor an
+    // overwrite made by hand lets them in.
+    for text in texts.iter() {
+        for target in planner::plan_denies(&text.permission_overwrites, &room.denylist) {
+            if dry_run {
+                info!("Would deny access for {} in #{}", target, text.name);
+                summary.actions.push(format!("Would deny {} access to <#{}>", target, text.id));
+            } else {
+                util::deny_access(ctx, text, target).await;
+            }
+        }
+    }
+
+    // Come back once the soonest grace period is over to revoke them for real.
+    grace.resync(ctx, guild_id);
+
+    summary
+}
+
+// rehearse_plan logs what carrying out a plan would change and adds it to the summary, without
+// changing anything. target is what the plan is for, a text-channel or the room's role.
+fn rehearse_plan(target: &str, plan: Plan, grace: &Grace, summary: &mut SyncSummary) {
+    for user_id in plan.revokes {
+        if grace.is_held(user_id) {
+            continue;
+        }
+        info!("Would revoke access for {} in {}", user_id, target);
+        summary.revoked.push(user_id);
+    }
+
+    for user_id in plan.grants {
+        info!("Would grant access for {} in {}", user_id, target);
+        summary.granted.push(user_id);
+    }
+}
+
+// Grace lets members that left a room keep access until their guild's grace period is over, in
+// case they're only reconnecting. Their grace period is cancelled once they're back.
+struct Grace {
+    room_id: u64,
+    period: Duration,
+    departures: Arc<Mutex<Departed>>,
+    // How long until the soonest grace period of the members being held is over.
+    next: Option<Duration>,
+    // Whether each member that left was held during this sync, so every text-channel agrees.
+    held: HashMap<UserId, bool>,
+}
+
+impl Grace {
+    async fn new(ctx: &Context, guild_id: GuildId, room: &Room) -> Grace {
+        let period = match util::store(ctx).await.find(guild_id) {
+            Some(serving) => Duration::from_secs(serving.grace_period),
+            None => Duration::from_secs(0),
+        };
+        let data = ctx.data.read().await;
+        let departures = data.get::<Departures>().expect("Failed to retrieve departures");
+
+        Grace {
+            room_id: room.id,
+            period,
+            departures: departures.clone(),
+            next: None,
+            held: HashMap::new(),
+        }
+    }
+
+    // hold checks if someone that left the room keeps access for now. Their grace period starts
+    // the first time they're seen gone. It's only checked once per sync, the same answer goes for
+    // every text-channel of the room.
+    fn hold(&mut self, user_id: UserId) -> bool {
+        if let Some(held) = self.held.get(&user_id) {
+            return *held;
+        }
+        let held = self.start_hold(user_id);
+        self.held.insert(user_id, held);
+        held
+    }
+
+    // start_hold starts or checks the grace period of someone that left the room.
+    fn start_hold(&mut self, user_id: UserId) -> bool {
+        if self.period.as_secs() == 0 {
+            return false;
+        }
+
+        let key = (self.room_id, user_id);
+        let elapsed;
+        {
+            let mut departures = self.departures.lock().unwrap();
+            elapsed = departures.members.entry(key).or_insert_with(Instant::now).elapsed();
+            if elapsed >= self.period {
+                departures.members.remove(&key);
+                return false;
+            }
+        }
+
+        self.wait(self.period - elapsed);
+        true
+    }
+
+    // close ends the grace period of everyone that left a room being torn down, they're revoked
+    // right away. A dry run only pretends to.
+    fn close(&mut self, dry_run: bool) {
+        self.period = Duration::from_secs(0);
+        if !dry_run {
+            let mut departures = self.departures.lock().unwrap();
+            departures.members.retain(|(room_id, _), _| *room_id != self.room_id);
+        }
+    }
+
+    // hold_room checks if a room everyone left is kept around for now. Its grace period starts the
+    // first time it's seen empty while it's live, and is cancelled once someone is back. A room
+    // that isn't live anymore, like an auto-room without text-channels, is never held.
+    fn hold_room(&mut self, occupied: bool, live: bool) -> bool {
+        let elapsed;
+        {
+            let mut departures = self.departures.lock().unwrap();
+            if occupied || !live || self.period.as_secs() == 0 {
+                departures.rooms.remove(&self.room_id);
+                return false;
+            }
+
+            let rooms = &mut departures.rooms;
+            elapsed = rooms.entry(self.room_id).or_insert_with(Instant::now).elapsed();
+            if elapsed >= self.period {
+                departures.rooms.remove(&self.room_id);
+                return false;
+            }
+        }
+
+        self.wait(self.period - elapsed);
+        true
+    }
+
+    // wait makes sure the room is synced again once remaining is over.
+    fn wait(&mut self, remaining: Duration) {
+        self.next = Some(self.next.map_or(remaining, |next| next.min(remaining)));
+    }
+
+    // resync syncs the room again once the soonest grace period is over, unless it's already
+    // waiting for one.
+    fn resync(&self, ctx: &Context, guild_id: GuildId) {
+        let delay;
+        match self.next {
+            Some(_delay) => delay = _delay,
+            None => return,
+        }
+
+        if self.departures.lock().unwrap().resyncs.insert(self.room_id) {
+            let departures = self.departures.clone();
+            tokio::spawn(resync(ctx.clone(), departures, guild_id, self.room_id, delay));
+        }
+    }
+
+    // is_room_held checks if a room everyone left would be kept around for now, without starting
+    // its grace period.
+    fn is_room_held(&self) -> bool {
+        if self.period.as_secs() == 0 {
+            return false;
+        }
+
+        let departures = self.departures.lock().unwrap();
+        match departures.rooms.get(&self.room_id) {
+            Some(emptied) => emptied.elapsed() < self.period,
+            None => false,
+        }
+    }
+
+    // is_held checks if someone that left the room would keep access for now, without starting
+    // their grace period.
+    fn is_held(&self, user_id: UserId) -> bool {
+        if self.period.as_secs() == 0 {
+            return false;
+        }
+
+        let departures = self.departures.lock().unwrap();
+        match departures.members.get(&(self.room_id, user_id)) {
+            Some(left) => left.elapsed() < self.period,
+            None => true,
+        }
+    }
+
+    // returned cancels the grace period of everyone that's back in the room.
+    fn returned(&self, user_ids: &[UserId]) {
+        let mut departures = self.departures.lock().unwrap();
+        departures.members.retain(|(room_id, user_id), _| {
+            *room_id != self.room_id || !user_ids.contains(user_id)
+        });
+    }
+}
+
+// resync reviews a room again after a delay. It's boxed since it's spawned from sync_room, which it
+// ends up calling again.
+fn resync(
+    ctx: Context,
+    departures: Arc<Mutex<Departed>>,
+    guild_id: GuildId,
+    room_id: u64,
+    delay: Duration,
+) -> BoxFuture<'static, ()> {
+    async move {
+        delay_for(delay).await;
+        departures.lock().unwrap().resyncs.remove(&room_id);
+        scheduler::schedule(&ctx, guild_id, room_id).await;
+    }
+    .boxed()
+}
+
+// add_allowed adds the members on a room's allowlist that aren't in its voice channels already.
+// Members with one of the allowed roles don't need adding, the role's overwrite lets them in.
+async fn add_allowed(
+    ctx: &Context,
+    guild_id: GuildId,
+    allowlist: &MemberList,
+    members: &mut Vec<Member>,
+) {
+    for user_id in allowlist.users.iter() {
+        if members.iter().any(|member| member.user.id == *user_id) {
+            continue;
+        }
+        match guild_id.member(ctx, *user_id).await {
+            Ok(member) => members.push(member),
+            Err(why) => warn!("Failed to get the member {} because\n{}", user_id, why),
+        }
+    }
+}
+
+// sync_role_overwrite lets a role into a text-channel. The overwrite only needs to be made once
+// per text-channel, or again when the template changed.
+async fn sync_role_overwrite(
+    ctx: &Context,
+    text: &GuildChannel,
+    role_id: RoleId,
+    template: &PermissionTemplate,
+) {
+    let kind = PermissionOverwriteType::Role(role_id);
+    let is_set = text
+        .permission_overwrites
+        .iter()
+        .any(|perm| perm.kind == kind && template.matches(perm));
+    if !is_set {
+        grant_role_access(ctx, text, role_id, template).await;
+    }
+}
+
+// adopt_overwrites finds the overwrites older versions of the bot made on a room's text-channels
+// before it kept track of them. Those only ever allowed reading the text-channel, but so might
+// ones staff made by hand, which is why only the adopt command uses this.
+pub fn adopt_overwrites(texts: &[GuildChannel]) -> Managed {
+    let mut managed = Managed::new();
+    for text in texts.iter() {
+        for perm in text.permission_overwrites.iter() {
+            if let PermissionOverwriteType::Member(user_id) = perm.kind {
+                if perm.allow == Permissions::READ_MESSAGES && perm.deny.is_empty() {
+                    managed.insert((text.id, user_id));
+                }
+            }
+        }
+    }
+    managed
+}
+
+// execute_role carries out the plan for who has the role of a room in role access mode.
+async fn execute_role(
+    ctx: &Context,
+    guild_id: GuildId,
+    role_id: RoleId,
+    plan: Plan,
+    grace: &mut Grace,
+    summary: &mut SyncSummary,
+) {
+    let holders = util::role_holders(ctx).await;
+
+    for user_id in plan.grants {
+        if grant_role(ctx, guild_id, role_id, user_id).await {
+            holders.lock().unwrap().give(role_id, user_id);
+            summary.granted.push(user_id);
+        }
+    }
+
+    for user_id in plan.revokes {
+        if grace.hold(user_id) {
+            continue;
+        }
+        if revoke_role(ctx, guild_id, role_id, user_id).await {
+            holders.lock().unwrap().take(role_id, user_id);
+            summary.revoked.push(user_id);
+        }
+    }
+}
+
+// role_holders gets who has the role of a room. The cache is asked if it has every member of the
+// guild, otherwise the members are fetched once and the holders are kept in RoleHolders.
+async fn role_holders(ctx: &Context, guild_id: GuildId, role_id: RoleId) -> Vec<UserId> {
+    let known = util::role_holders(ctx).await;
+    let known_holders = known.lock().unwrap().get(role_id);
+    if let Some(holders) = known_holders {
+        return holders;
+    }
+
+    let cached = ctx
+        .cache
+        .guild_field(guild_id, |guild| {
+            let holders: Vec<UserId> = guild
+                .members
+                .values()
+                .filter(|member| member.roles.contains(&role_id))
+                .map(|member| member.user.id)
+                .collect();
+            (holders, guild.members.len() as u64 >= guild.member_count)
+        })
+        .await;
+
+    let cached_holders;
+    match cached {
+        Some((_holders, true)) => return _holders,
+        Some((_holders, false)) => cached_holders = _holders,
+        None => cached_holders = Vec::new(),
+    }
+
+    // If the members couldn't be fetched then only the holders that were cached are known. They're
+    // not remembered, so the others lose the role once the members can be fetched again.
+    match fetch_holders(ctx, guild_id, role_id).await {
+        Some(holders) => {
+            known.lock().unwrap().set(role_id, &holders);
+            holders
+        }
+        None => cached_holders,
+    }
+}
+
+// fetch_holders fetches every member of a guild to find who has a role. Returns None if they
+// couldn't all be fetched.
+async fn fetch_holders(ctx: &Context, guild_id: GuildId, role_id: RoleId) -> Option<Vec<UserId>> {
+    let mut holders = Vec::<UserId>::new();
+    let mut after: Option<UserId> = None;
+    loop {
+        let members;
+        match guild_id.members(ctx, Some(MEMBERS_PER_PAGE), after).await {
+            Ok(_members) => members = _members,
+            Err(why) => {
+                warn!("Failed to get the members of {} because\n{}", guild_id, why);
+                return None;
+            }
+        }
+
+        for member in members.iter() {
+            if member.roles.contains(&role_id) {
+                holders.push(member.user.id);
+            }
+        }
 
-                // Remove them from the text channel if they're not
-                // in the voice channel.
-                if !is_in_vc {
-                    if perm.allow.read_messages() {
-                        revoke_access(ctx, &text, user_id).await;
-                    }
+        match members.last() {
+            Some(last) if members.len() as u64 == MEMBERS_PER_PAGE => after = Some(last.user.id),
+            _ => return Some(holders),
+        }
+    }
+}
 
+// forget_rooms cleans up after removed rooms. The roles of rooms in role access mode are deleted,
+// they were made for the room and are of no use without it. The overwrites the bot kept track of
+// are forgotten so they don't pile up in the store.
+pub async fn forget_rooms(ctx: &Context, guild_id: GuildId, removed: &[Room]) {
+    let store = util::store(ctx).await;
+    for room in removed.iter() {
+        if let Err(why) = store.put_managed(room.id, &Managed::new()) {
+            warn!("Failed to forget the overwrites of room {} because\n{}", room.id, why);
+        }
 
-                // Otherwise if they're in the vc and have access to the
-                // text-channel then remove them from the vec. The vec
-                // will be iterated through later and add the remaining
-                // members that can't see the text-channel
-                } else {
-                    if perm.allow.read_messages() {
-                        members_in_vc.remove(i);
-                    }
+        if let Access::Role { role_id } = room.access {
+            if let Err(why) = guild_id.delete_role(ctx, role_id).await {
+                warn!("Failed to delete the role {} because\n{}", role_id, why);
+            }
+            util::role_holders(ctx).await.lock().unwrap().forget(role_id);
+        }
+    }
+}
+
+// members_in_room gets everyone in the voice channels of a room, going by the VoiceIndex. If a
+// voice channel couldn't be fetched then the people in it aren't known, so None is returned rather
+// than an incomplete list. Those in the voice channels whose member couldn't be fetched are skipped
+// and returned on their own, so one failed lookup doesn't hold up the whole room.
+async fn members_in_room(
+    ctx: &Context,
+    room: &Room,
+    voices: &[GuildChannel],
+) -> Option<(Vec<Member>, Vec<UserId>)> {
+    if voices.len() != room.voice_ids.len() {
+        return None;
+    }
+
+    let mut user_ids = Vec::<UserId>::new();
+    {
+        let index = util::voice_index(ctx).await;
+        let index = index.read().unwrap();
+        for voice in voices.iter() {
+            for user_id in index.members(voice.id) {
+                if !user_ids.contains(&user_id) {
+                    user_ids.push(user_id);
                 }
             }
-            _ => continue,
         }
     }
 
-    // members_in_vc at this point is considered as in the voice channel,
-    // but they don't have access to the text channel
-    for member in members_in_vc.iter() {
-        grant_access(ctx, &text, member.user.id).await;
+    let mut members_in_vc = Vec::<Member>::new();
+    let mut unknown = Vec::<UserId>::new();
+    for user_id in user_ids {
+        match voices[0].guild_id.member(ctx, user_id).await {
+            Ok(member) => members_in_vc.push(member),
+            Err(why) => {
+                warn!("Failed to get the member {} because\n{}", user_id, why);
+                unknown.push(user_id);
+            }
+        }
     }
+
+    Some((members_in_vc, unknown))
 }
 
-// Check if a given user ID is a voice channel.
-fn in_vc(user: UserId, members: &Vec<Member>) -> (bool, usize) {
-    let mut i: usize = 0;
-    for member in members.iter() {
-        if member.user.id == user {
-            return (true, i);
+// execute_text carries out the plan for a single text-channel of a room, keeping track of which
+// overwrites the bot made in managed.
+async fn execute_text(
+    ctx: &Context,
+    text: &GuildChannel,
+    template: &PermissionTemplate,
+    plan: Plan,
+    managed: &mut Managed,
+    grace: &mut Grace,
+    summary: &mut SyncSummary,
+) {
+    for user_id in plan.forget {
+        managed.remove(&(text.id, user_id));
+    }
+
+    for user_id in plan.revokes {
+        if grace.hold(user_id) {
+            continue;
+        }
+        if revoke_access(ctx, text, user_id).await {
+            managed.remove(&(text.id, user_id));
+            summary.revoked.push(user_id);
+        }
+    }
+
+    for user_id in plan.grants {
+        if grant_access(ctx, text, user_id, template).await {
+            managed.insert((text.id, user_id));
+            summary.granted.push(user_id);
         }
-        i += 1;
     }
-    return (false, 0);
 }
 
-// Get the text-channel associated with a voice channel in a guild (Serving).
-fn get_room(serving: &Serving, id: &ChannelId) -> Option<Room> {
+// Get the room a voice channel is part of in a guild (Serving).
+pub fn get_room(serving: &Serving, id: &ChannelId) -> Option<Room> {
     for room in serving.rooms.iter() {
-        if room.voice_id.as_u64() == id.as_u64() {
+        if room.voice_ids.contains(id) {
             return Some(room.clone());
         }
     }
@@ -1,11 +1,64 @@
-use crate::bot::core;
-use crate::config::{Config, Serving};
-use log::info;
+use crate::bot::{core, rooms, scheduler, slash, util};
+use crate::config::Serving;
+use crate::store::RoomStore;
+use log::{info, warn};
+use serde_json::Value;
 use serenity::model::prelude::*;
 use serenity::prelude::*;
 
 pub struct Handler;
 
+// pair_categories pairs the channels of a guild's linked categories that aren't linked yet, such
+// as ones created while the bot was offline.
+async fn pair_categories(ctx: &Context, store: &dyn RoomStore, serving: &mut Serving) {
+    let channels: Vec<GuildChannel>;
+    match serving.guild_id.channels(ctx).await {
+        Ok(_channels) => channels = _channels.into_iter().map(|(_, c)| c).collect(),
+        Err(why) => {
+            warn!("Failed to get the channels of {} because\n{}", serving.guild_id, why);
+            return;
+        }
+    }
+
+    let mut paired = 0;
+    for link in serving.categories.clone().iter() {
+        paired += rooms::pair_category(serving, link.category_id, &channels);
+    }
+
+    if paired > 0 {
+        if let Err(why) = store.put(serving) {
+            warn!("Failed to save the rooms of {} because\n{}", serving.guild_id, why);
+        }
+    }
+}
+
+// unlink_deleted unlinks a channel that was deleted from the guild.
+async fn unlink_deleted(ctx: &Context, guild_id: GuildId, channel_id: ChannelId, name: &str) {
+    let store = util::store(ctx).await;
+    let mut serving;
+    match store.find(guild_id) {
+        Some(_serving) => serving = _serving,
+        None => return,
+    }
+
+    if let Some(removed) = rooms::unlink(&mut serving, channel_id) {
+        info!("Unlinked {} since it was deleted", name);
+        if let Err(why) = store.put(&serving) {
+            warn!("Failed to save the rooms of {} because\n{}", serving.guild_id, why);
+            return;
+        }
+        core::forget_rooms(ctx, guild_id, &removed).await;
+    }
+}
+
+// seed_voice_index fills the VoiceIndex with everyone in the voice channels of a guild.
+async fn seed_voice_index(ctx: &Context, guild: &Guild) {
+    let index = util::voice_index(ctx).await;
+    let mut index = index.write().unwrap();
+    let states = guild.voice_states.iter().map(|(user_id, state)| (*user_id, state.channel_id));
+    index.seed(guild.id, states);
+}
+
 fn skip_review(new: &VoiceState, old_opt: &Option<VoiceState>) -> bool {
     if old_opt.is_none() {
         return false;
@@ -28,10 +81,94 @@ Ready as {}
  * Invite URL: {}",
             us.tag(),
             guilds.len(),
-            us.invite_url(&ctx, perms).await.unwrap(),
+            // Slash commands need the applications.commands scope on top of bot.
+            us.invite_url(&ctx, perms)
+                .await
+                .unwrap()
+                .replace("scope=bot", "scope=bot%20applications.commands"),
         );
     }
 
+    // Rooms are reconciled once the cache is ready rather than on ready, since the VoiceIndex is
+    // seeded with the voice states in the cache.
+    async fn cache_ready(&self, ctx: Context, guilds: Vec<GuildId>) {
+        for guild_id in guilds {
+            if let Some(guild) = ctx.cache.guild(guild_id).await {
+                seed_voice_index(&ctx, &guild).await;
+            }
+            slash::register(&ctx, guild_id).await;
+        }
+
+        let store = util::store(&ctx).await;
+        let mut serving;
+        match store.all() {
+            Ok(_serving) => serving = _serving,
+            Err(why) => {
+                warn!("Failed to get the rooms of every guild because\n{}", why);
+                return;
+            }
+        }
+        for _serving in serving.iter_mut() {
+            if !_serving.categories.is_empty() {
+                pair_categories(&ctx, store.as_ref(), _serving).await;
+            }
+        }
+
+        core::reconcile(&ctx, serving).await;
+    }
+
+    // Guilds that become available later on, or again after an outage, bring their voice states.
+    // Guilds the bot was just added to get the slash commands too.
+    async fn guild_create(&self, ctx: Context, guild: Guild, is_new: bool) {
+        seed_voice_index(&ctx, &guild).await;
+        if is_new {
+            slash::register(&ctx, guild.id).await;
+        }
+    }
+
+    // New channels in a linked category are paired right away.
+    async fn channel_create(&self, ctx: Context, channel: &GuildChannel) {
+        let category_id;
+        match channel.category_id {
+            Some(_category_id) => category_id = _category_id,
+            None => return,
+        }
+
+        let store = util::store(&ctx).await;
+        let mut serving;
+        match store.find(channel.guild_id) {
+            Some(_serving) => serving = _serving,
+            None => return,
+        }
+
+        if serving.categories.iter().any(|link| link.category_id == category_id) {
+            pair_categories(&ctx, store.as_ref(), &mut serving).await;
+        }
+    }
+
+    // Deleted channels are unlinked from their room.
+    async fn channel_delete(&self, ctx: Context, channel: &GuildChannel) {
+        unlink_deleted(&ctx, channel.guild_id, channel.id, &channel.name).await;
+    }
+
+    // Deleting a linked category unlinks it.
+    async fn category_delete(&self, ctx: Context, category: &ChannelCategory) {
+        unlink_deleted(&ctx, category.guild_id, category.id, &category.name).await;
+    }
+
+    // Staff giving or taking the role of a room by hand keeps its known holders up to date.
+    async fn guild_member_update(&self, ctx: Context, _old: Option<Member>, new: Member) {
+        let holders = util::role_holders(&ctx).await;
+        holders.lock().unwrap().update(new.user.id, &new.roles);
+    }
+
+    // Serenity doesn't know about interactions so slash commands arrive as unknown events.
+    async fn unknown(&self, ctx: Context, name: String, raw: Value) {
+        if name == "INTERACTION_CREATE" {
+            slash::interaction(&ctx, &raw).await;
+        }
+    }
+
     async fn voice_state_update(
         &self,
         ctx: Context,
@@ -39,25 +176,26 @@ Ready as {}
         opt_old: Option<VoiceState>,
         new: VoiceState,
     ) {
+        if let Some(guild_id) = opt_guild_id {
+            let index = util::voice_index(&ctx).await;
+            let mut index = index.write().unwrap();
+            index.update(guild_id, new.user_id, new.channel_id);
+        }
+
         if (skip_review(&new, &opt_old)) {
             return;
         }
 
         let serving: Serving;
-        {
-            let data = ctx.data.read().await;
-            let config = data.get::<Config>().expect("Failed to retrieve config");
-
-            match opt_guild_id {
-                Some(guild_id) => {
-                    if let Some(_serving) = config.serving.get(guild_id.as_u64()) {
-                        serving = _serving.clone();
-                    } else {
-                        return;
-                    }
+        match opt_guild_id {
+            Some(guild_id) => {
+                if let Some(_serving) = util::store(&ctx).await.find(guild_id) {
+                    serving = _serving;
+                } else {
+                    return;
                 }
-                None => return,
             }
+            None => return,
         }
 
         // Review the voice channel they left
@@ -66,6 +204,16 @@ Ready as {}
             let serve_clone = serving.clone();
             core::review_state(&ctx_clone, &serve_clone, &old).await;
         }
+
+        // If they joined a hub then give them a room of their own. Moving them into it will
+        // review the room.
+        if let Some(channel_id) = new.channel_id {
+            if serving.hubs.contains(&channel_id) {
+                scheduler::create_room(&ctx, serving.guild_id, channel_id, new.user_id).await;
+                return;
+            }
+        }
+
         // Review the voice channel they joined
         core::review_state(&ctx, &serving, &new).await;
     }
@@ -0,0 +1,58 @@
+use serenity::model::prelude::*;
+use serenity::prelude::TypeMapKey;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+// RoleHolders is who has the role of each room in role access mode, for guilds too big for the
+// cache to have every member. Their members are fetched once per role and the holders are kept up
+// to date from then on, as the bot and staff give and take the role.
+#[derive(Debug, Default)]
+pub struct RoleHolders {
+    roles: HashMap<RoleId, HashSet<UserId>>,
+}
+
+impl TypeMapKey for RoleHolders {
+    type Value = Arc<Mutex<RoleHolders>>;
+}
+
+impl RoleHolders {
+    // get returns who has a role, if they're known.
+    pub fn get(&self, role_id: RoleId) -> Option<Vec<UserId>> {
+        self.roles.get(&role_id).map(|holders| holders.iter().copied().collect())
+    }
+
+    // set remembers who has a role.
+    pub fn set(&mut self, role_id: RoleId, holders: &[UserId]) {
+        self.roles.insert(role_id, holders.iter().copied().collect());
+    }
+
+    // update keeps the known holders up to date with the roles a member has now.
+    pub fn update(&mut self, user_id: UserId, roles: &[RoleId]) {
+        for (role_id, holders) in self.roles.iter_mut() {
+            if roles.contains(role_id) {
+                holders.insert(user_id);
+            } else {
+                holders.remove(&user_id);
+            }
+        }
+    }
+
+    // give notes that someone was given a role.
+    pub fn give(&mut self, role_id: RoleId, user_id: UserId) {
+        if let Some(holders) = self.roles.get_mut(&role_id) {
+            holders.insert(user_id);
+        }
+    }
+
+    // take notes that someone's role was taken away.
+    pub fn take(&mut self, role_id: RoleId, user_id: UserId) {
+        if let Some(holders) = self.roles.get_mut(&role_id) {
+            holders.remove(&user_id);
+        }
+    }
+
+    // forget forgets a role that was deleted.
+    pub fn forget(&mut self, role_id: RoleId) {
+        self.roles.remove(&role_id);
+    }
+}
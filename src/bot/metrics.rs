@@ -0,0 +1,60 @@
+use serenity::prelude::TypeMapKey;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Metrics count how often the lookups of a sync were answered by the cache rather than over HTTP.
+#[derive(Default)]
+pub struct Metrics {
+    pub channels: Counter,
+    pub users: Counter,
+}
+
+impl TypeMapKey for Metrics {
+    type Value = Arc<Metrics>;
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, " * Channels: {}\n * Users: {}", self.channels, self.users)
+    }
+}
+
+// Counter counts the cache hits and misses of one kind of lookup.
+#[derive(Default)]
+pub struct Counter {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Counter {
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // hit_rate is the percentage of lookups the cache answered.
+    pub fn hit_rate(&self) -> f64 {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let total = hits + self.misses.load(Ordering::Relaxed);
+        if total == 0 {
+            return 0.0;
+        }
+        hits as f64 * 100.0 / total as f64
+    }
+}
+
+impl fmt::Display for Counter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} from cache, {} over HTTP ({:.1}% hit rate)",
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+            self.hit_rate(),
+        )
+    }
+}
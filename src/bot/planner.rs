@@ -0,0 +1,410 @@
+use crate::bot::rooms::Target;
+use crate::config::{MemberList, PermissionTemplate};
+use serenity::model::prelude::*;
+use std::collections::BTreeSet;
+
+// The planner works out what syncing a room has to change without touching Discord, so sync_room
+// only has to fetch what the planner needs and carry out the plan.
+
+// Occupant is someone that should be able to see a room's text-channels, either because they're
+// in its voice channels or on its allowlist.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Occupant {
+    pub user_id: UserId,
+    pub roles: Vec<RoleId>,
+    // False if their member couldn't be fetched, so their roles aren't known. They keep the access
+    // they have but aren't given any, since one of their roles might be on the denylist.
+    pub known: bool,
+}
+
+impl From<&Member> for Occupant {
+    fn from(member: &Member) -> Occupant {
+        Occupant {
+            user_id: member.user.id,
+            roles: member.roles.clone(),
+            known: true,
+        }
+    }
+}
+
+// Policy is what a room wants for the people in it.
+#[derive(Debug, Clone, Copy)]
+pub struct Policy<'a> {
+    pub template: &'a PermissionTemplate,
+    pub denylist: &'a MemberList,
+}
+
+impl<'a> Policy<'a> {
+    // wants checks if an occupant should be let in, the denylist keeps them out regardless.
+    fn wants(&self, occupant: &Occupant) -> bool {
+        !self.denylist.contains(occupant.user_id, &occupant.roles)
+    }
+}
+
+// Plan is what has to change for a room's text-channel or role to match the people in it.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Plan {
+    pub grants: Vec<UserId>,
+    pub revokes: Vec<UserId>,
+    // Overwrites the bot made that were deleted by hand, they're forgotten so one made by staff
+    // later on for the same member isn't mistaken for the bot's.
+    pub forget: Vec<UserId>,
+}
+
+// plan_text plans the member overwrites of one text-channel. Only the overwrites in managed are
+// ever revoked or replaced, the ones staff made by hand are left alone. Users on the denylist
+// aren't revoked, their overwrite is replaced by the deny of plan_denies instead.
+pub fn plan_text(
+    text_id: ChannelId,
+    overwrites: &[PermissionOverwrite],
+    occupants: &[Occupant],
+    managed: &BTreeSet<(ChannelId, UserId)>,
+    policy: &Policy,
+) -> Plan {
+    let mut plan = Plan::default();
+    let wanted: Vec<&Occupant> = occupants.iter().filter(|o| policy.wants(o)).collect();
+    let is_wanted = |user_id: UserId| wanted.iter().any(|o| o.user_id == user_id);
+    // Everyone that already has the overwrite they should have, or one the bot can't touch.
+    let mut settled = Vec::<UserId>::new();
+
+    for perm in overwrites.iter() {
+        let user_id = match perm.kind {
+            PermissionOverwriteType::Member(user_id) => user_id,
+            _ => continue,
+        };
+
+        if policy.denylist.users.contains(&user_id) {
+            if managed.contains(&(text_id, user_id)) {
+                plan.forget.push(user_id);
+            }
+        } else if !managed.contains(&(text_id, user_id)) {
+            settled.push(user_id);
+        } else if !is_wanted(user_id) {
+            plan.revokes.push(user_id);
+        } else if policy.template.matches(perm) {
+            settled.push(user_id);
+        }
+    }
+
+    for (id, user_id) in managed.iter() {
+        let exists = overwrites
+            .iter()
+            .any(|perm| perm.kind == PermissionOverwriteType::Member(*user_id));
+        if *id == text_id && !exists && !plan.forget.contains(user_id) {
+            plan.forget.push(*user_id);
+        }
+    }
+
+    for occupant in wanted.iter().filter(|o| o.known) {
+        let user_id = occupant.user_id;
+        if !settled.contains(&user_id) && !plan.grants.contains(&user_id) {
+            plan.grants.push(user_id);
+        }
+    }
+
+    plan
+}
+
+// plan_denies plans the overwrites that keep everyone on the denylist out of one text-channel,
+// whether they're in the voice channels or not. It returns the users and roles whose overwrite
+// doesn't deny reading the text-channel yet.
+pub fn plan_denies(overwrites: &[PermissionOverwrite], denylist: &MemberList) -> Vec<Target> {
+    let users = denylist.users.iter().map(|id| Target::User(*id));
+    let roles = denylist.roles.iter().map(|id| Target::Role(*id));
+    users
+        .chain(roles)
+        .filter(|target| !overwrites.iter().any(|perm| is_deny(perm, *target)))
+        .collect()
+}
+
+// is_deny checks if an overwrite is the deny_overwrite of target.
+pub fn is_deny(perm: &PermissionOverwrite, target: Target) -> bool {
+    let deny = deny_overwrite(target);
+    perm.kind == deny.kind && perm.allow == deny.allow && perm.deny == deny.deny
+}
+
+// deny_overwrite is the overwrite that keeps a user or role on the denylist out of a text-channel.
+pub fn deny_overwrite(target: Target) -> PermissionOverwrite {
+    PermissionOverwrite {
+        allow: Permissions::empty(),
+        deny: Permissions::READ_MESSAGES,
+        kind: target.kind(),
+    }
+}
+
+// plan_role plans who has the role of a room in role access mode. holders are the members that
+// have the role right now.
+pub fn plan_role(
+    role_id: RoleId,
+    holders: &[UserId],
+    occupants: &[Occupant],
+    policy: &Policy,
+) -> Plan {
+    let mut plan = Plan::default();
+    let wanted: Vec<&Occupant> = occupants.iter().filter(|o| policy.wants(o)).collect();
+
+    for occupant in wanted.iter().filter(|o| o.known) {
+        let has_role = occupant.roles.contains(&role_id) || holders.contains(&occupant.user_id);
+        if !has_role && !plan.grants.contains(&occupant.user_id) {
+            plan.grants.push(occupant.user_id);
+        }
+    }
+
+    for user_id in holders.iter() {
+        if !wanted.iter().any(|o| o.user_id == *user_id) {
+            plan.revokes.push(*user_id);
+        }
+    }
+
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: ChannelId = ChannelId(1);
+    const OTHER_TEXT: ChannelId = ChannelId(2);
+    const ROOM_ROLE: RoleId = RoleId(10);
+    const MUTED: RoleId = RoleId(11);
+
+    // Setup is the template and denylist of the room being planned, most tests use the defaults.
+    #[derive(Default)]
+    struct Setup {
+        template: PermissionTemplate,
+        denylist: MemberList,
+    }
+
+    impl Setup {
+        fn allowing(allow: Permissions) -> Setup {
+            let mut setup = Setup::default();
+            setup.template.allow |= allow;
+            setup
+        }
+
+        fn denying(users: &[u64], roles: &[RoleId]) -> Setup {
+            let mut setup = Setup::default();
+            setup.denylist.users = self::users(users);
+            setup.denylist.roles = roles.to_vec();
+            setup
+        }
+
+        fn policy(&self) -> Policy<'_> {
+            Policy {
+                template: &self.template,
+                denylist: &self.denylist,
+            }
+        }
+
+        fn text(
+            &self,
+            overwrites: &[PermissionOverwrite],
+            occupants: &[Occupant],
+            managed: &[u64],
+        ) -> Plan {
+            plan_text(TEXT, overwrites, occupants, &self::managed(managed), &self.policy())
+        }
+
+        fn denies(&self, overwrites: &[PermissionOverwrite]) -> Vec<Target> {
+            plan_denies(overwrites, &self.denylist)
+        }
+
+        fn role(&self, holders: &[u64], occupants: &[Occupant]) -> Plan {
+            plan_role(ROOM_ROLE, &users(holders), occupants, &self.policy())
+        }
+    }
+
+    fn occupant(id: u64) -> Occupant {
+        with_roles(id, &[])
+    }
+
+    fn with_roles(id: u64, roles: &[RoleId]) -> Occupant {
+        Occupant {
+            user_id: UserId(id),
+            roles: roles.to_vec(),
+            known: true,
+        }
+    }
+
+    // unknown is an occupant whose member couldn't be fetched.
+    fn unknown(id: u64) -> Occupant {
+        Occupant {
+            known: false,
+            ..occupant(id)
+        }
+    }
+
+    // overwrite is the overwrite the bot makes with the default template.
+    fn overwrite(id: u64) -> PermissionOverwrite {
+        PermissionTemplate::default().overwrite(PermissionOverwriteType::Member(UserId(id)))
+    }
+
+    fn managed(ids: &[u64]) -> BTreeSet<(ChannelId, UserId)> {
+        ids.iter().map(|id| (TEXT, UserId(*id))).collect()
+    }
+
+    fn users(ids: &[u64]) -> Vec<UserId> {
+        ids.iter().map(|id| UserId(*id)).collect()
+    }
+
+    #[test]
+    fn grants_everyone_in_an_empty_text_channel() {
+        let plan = Setup::default().text(&[], &[occupant(1), occupant(2)], &[]);
+
+        assert_eq!(plan.grants, users(&[1, 2]));
+        assert!(plan.revokes.is_empty());
+        assert!(plan.forget.is_empty());
+    }
+
+    #[test]
+    fn nothing_changes_when_in_sync() {
+        let overwrites = vec![overwrite(1), overwrite(2)];
+        let plan = Setup::default().text(&overwrites, &[occupant(1), occupant(2)], &[1, 2]);
+
+        assert_eq!(plan, Plan::default());
+    }
+
+    #[test]
+    fn revokes_managed_overwrites_of_people_that_left() {
+        let overwrites = vec![overwrite(1), overwrite(2)];
+        let plan = Setup::default().text(&overwrites, &[occupant(1)], &[1, 2]);
+
+        assert!(plan.grants.is_empty());
+        assert_eq!(plan.revokes, users(&[2]));
+    }
+
+    #[test]
+    fn leaves_overwrites_made_by_hand_alone() {
+        // 2 was let in by staff and isn't in the voice channel, 3 was let in by staff with
+        // different permissions and is in the voice channel.
+        let mut by_hand = overwrite(3);
+        by_hand.allow |= Permissions::MANAGE_MESSAGES;
+        let plan = Setup::default().text(&[overwrite(2), by_hand], &[occupant(3)], &[]);
+
+        assert_eq!(plan, Plan::default());
+    }
+
+    #[test]
+    fn regrants_overwrites_that_no_longer_match_the_template() {
+        let setup = Setup::allowing(Permissions::SEND_MESSAGES);
+        let plan = setup.text(&[overwrite(1)], &[occupant(1)], &[1]);
+
+        assert_eq!(plan.grants, users(&[1]));
+        assert!(plan.revokes.is_empty());
+    }
+
+    #[test]
+    fn ignores_role_overwrites() {
+        let role = PermissionOverwriteType::Role(ROOM_ROLE);
+        let overwrites = vec![PermissionTemplate::default().overwrite(role)];
+        let plan = Setup::default().text(&overwrites, &[], &[]);
+
+        assert_eq!(plan, Plan::default());
+    }
+
+    #[test]
+    fn forgets_managed_overwrites_deleted_by_hand() {
+        let setup = Setup::default();
+        let mut managed = managed(&[1, 2]);
+        managed.insert((OTHER_TEXT, UserId(3)));
+        let plan = plan_text(TEXT, &[overwrite(1)], &[occupant(1)], &managed, &setup.policy());
+
+        // 3 belongs to another text-channel so it isn't this plan's business.
+        assert_eq!(plan.forget, users(&[2]));
+        assert!(plan.grants.is_empty());
+        assert!(plan.revokes.is_empty());
+    }
+
+    #[test]
+    fn keeps_denied_users_and_roles_out() {
+        let overwrites = vec![overwrite(2), overwrite(3)];
+        let occupants = vec![
+            occupant(1),
+            occupant(2),
+            with_roles(3, &[MUTED]),
+            with_roles(4, &[MUTED]),
+        ];
+        let plan = Setup::denying(&[2], &[MUTED]).text(&overwrites, &occupants, &[2, 3]);
+
+        // 2's overwrite is replaced by a deny rather than revoked, so the bot lets go of it.
+        assert_eq!(plan.grants, users(&[1]));
+        assert_eq!(plan.revokes, users(&[3]));
+        assert_eq!(plan.forget, users(&[2]));
+    }
+
+    #[test]
+    fn leaves_the_deny_of_denied_users_alone() {
+        let deny = deny_overwrite(Target::User(UserId(2)));
+        let plan = Setup::denying(&[2], &[]).text(&[deny], &[occupant(2)], &[]);
+
+        assert_eq!(plan, Plan::default());
+    }
+
+    #[test]
+    fn denies_everyone_on_the_denylist() {
+        let plan = Setup::denying(&[2], &[MUTED]).denies(&[]);
+
+        assert_eq!(plan, vec![Target::User(UserId(2)), Target::Role(MUTED)]);
+    }
+
+    #[test]
+    fn denies_only_once() {
+        // 3 still has an overwrite that lets them in.
+        let overwrites = vec![deny_overwrite(Target::User(UserId(2))), overwrite(3)];
+        let plan = Setup::denying(&[2, 3], &[]).denies(&overwrites);
+
+        assert_eq!(plan, vec![Target::User(UserId(3))]);
+    }
+
+    #[test]
+    fn keeps_but_never_grants_unknown_occupants() {
+        // 1 keeps their overwrite, 2 doesn't get one and 3's outdated one isn't replaced.
+        let mut outdated = overwrite(3);
+        outdated.allow |= Permissions::SEND_MESSAGES;
+        let overwrites = vec![overwrite(1), outdated];
+        let occupants = vec![unknown(1), unknown(2), unknown(3)];
+        let plan = Setup::default().text(&overwrites, &occupants, &[1, 3]);
+
+        assert_eq!(plan, Plan::default());
+    }
+
+    #[test]
+    fn grants_each_occupant_once() {
+        let plan = Setup::default().text(&[], &[occupant(1), occupant(1)], &[]);
+
+        assert_eq!(plan.grants, users(&[1]));
+    }
+
+    #[test]
+    fn gives_the_role_to_occupants_without_it() {
+        let occupants = vec![occupant(1), with_roles(2, &[ROOM_ROLE])];
+        let plan = Setup::default().role(&[2], &occupants);
+
+        assert_eq!(plan.grants, users(&[1]));
+        assert!(plan.revokes.is_empty());
+    }
+
+    #[test]
+    fn takes_the_role_from_people_that_left() {
+        let plan = Setup::default().role(&[1, 2], &[with_roles(1, &[ROOM_ROLE])]);
+
+        assert!(plan.grants.is_empty());
+        assert_eq!(plan.revokes, users(&[2]));
+    }
+
+    #[test]
+    fn keeps_but_never_gives_the_role_to_unknown_occupants() {
+        let plan = Setup::default().role(&[1], &[unknown(1), unknown(2)]);
+
+        assert_eq!(plan, Plan::default());
+    }
+
+    #[test]
+    fn takes_the_role_from_denied_occupants() {
+        let occupants = vec![with_roles(1, &[ROOM_ROLE, MUTED])];
+        let plan = Setup::denying(&[], &[MUTED]).role(&[1], &occupants);
+
+        assert!(plan.grants.is_empty());
+        assert_eq!(plan.revokes, users(&[1]));
+    }
+}
@@ -0,0 +1,318 @@
+use crate::config::{CategoryLink, MemberList, Room, RoomKind, Serving};
+use serenity::model::prelude::*;
+use std::fmt;
+
+// PERMISSIONS are the permissions a room's template can allow or deny, by the names the
+// permissions command takes.
+pub const PERMISSIONS: &[(&'static str, Permissions)] = &[
+    ("send_messages", Permissions::SEND_MESSAGES),
+    ("send_tts_messages", Permissions::SEND_TTS_MESSAGES),
+    ("embed_links", Permissions::EMBED_LINKS),
+    ("attach_files", Permissions::ATTACH_FILES),
+    ("read_message_history", Permissions::READ_MESSAGE_HISTORY),
+    ("add_reactions", Permissions::ADD_REACTIONS),
+    ("use_external_emojis", Permissions::USE_EXTERNAL_EMOJIS),
+    ("mention_everyone", Permissions::MENTION_EVERYONE),
+    ("manage_messages", Permissions::MANAGE_MESSAGES),
+];
+
+// The pattern of a category link without one, the text channel is named after the voice channel.
+pub const DEFAULT_PATTERN: &'static str = "{name}";
+
+// The room-linking rules live here rather than in the commands so every command interface links
+// and unlinks rooms the same way. The commands only parse what the user gave them.
+
+// LinkError is why a voice channel and text channel couldn't be linked.
+#[derive(Debug)]
+pub enum LinkError {
+    NotVoice,
+    NotText,
+    VoiceLinked,
+    AlreadyLinked,
+    DifferentRooms,
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let res = match self {
+            LinkError::NotVoice => "That isn't a voice channel.",
+            LinkError::NotText => "That isn't a text channel.",
+            LinkError::VoiceLinked => "That voice channel is already linked with something.",
+            LinkError::AlreadyLinked => "Those channels are already linked together.",
+            LinkError::DifferentRooms => "Those channels are already linked with other channels.",
+        };
+        f.write_str(res)
+    }
+}
+
+// link establishes a room between a voice channel and text channel of a guild. If one of them is
+// already part of a room then the other is added to that room, so a voice channel can reveal
+// several text channels and several voice channels can share a text channel.
+pub fn link(
+    serving: &mut Serving,
+    voice: &GuildChannel,
+    text: &GuildChannel,
+) -> Result<(), LinkError> {
+    if voice.kind != ChannelType::Voice {
+        return Err(LinkError::NotVoice);
+    }
+    if text.kind != ChannelType::Text {
+        return Err(LinkError::NotText);
+    }
+
+    let voice_room = serving.rooms.iter().position(|room| room.voice_ids.contains(&voice.id));
+    let text_room = serving.rooms.iter().position(|room| room.text_ids.contains(&text.id));
+
+    match (voice_room, text_room) {
+        (Some(a), Some(b)) if a == b => return Err(LinkError::AlreadyLinked),
+        (Some(_), Some(_)) => return Err(LinkError::DifferentRooms),
+        (Some(i), None) => serving.rooms[i].text_ids.push(text.id),
+        (None, Some(i)) => serving.rooms[i].voice_ids.push(voice.id),
+        (None, None) => serving.rooms.push(Room::new(vec![voice.id], vec![text.id])),
+    }
+    Ok(())
+}
+
+// auto_room makes a voice channel an auto-room. The bot makes its text channel when the first
+// person joins and tears it down when the last person leaves, see core::review_room.
+pub fn auto_room(
+    serving: &mut Serving,
+    voice: &GuildChannel,
+    archive: bool,
+) -> Result<(), LinkError> {
+    if voice.kind != ChannelType::Voice {
+        return Err(LinkError::NotVoice);
+    }
+    if is_linked(serving, voice.id) {
+        return Err(LinkError::VoiceLinked);
+    }
+
+    let mut room = Room::new(vec![voice.id], Vec::new());
+    room.kind = RoomKind::Auto { archive };
+    serving.rooms.push(room);
+    Ok(())
+}
+
+// add_hub makes a voice channel a hub. Whoever joins it gets a voice channel and text-channel of
+// their own, see core::create_temporary_room.
+pub fn add_hub(serving: &mut Serving, voice: &GuildChannel) -> Result<(), LinkError> {
+    if voice.kind != ChannelType::Voice {
+        return Err(LinkError::NotVoice);
+    }
+    if is_linked(serving, voice.id) || serving.hubs.contains(&voice.id) {
+        return Err(LinkError::VoiceLinked);
+    }
+
+    serving.hubs.push(voice.id);
+    Ok(())
+}
+
+// remove_room removes a room from the guild's rooms. The room is found by its ID. Returns false
+// if it isn't there anymore.
+pub fn remove_room(serving: &mut Serving, room: &Room) -> bool {
+    let before = serving.rooms.len();
+    serving.rooms.retain(|existing| existing.id != room.id);
+    serving.rooms.len() != before
+}
+
+// parse_permission gets a permission by its name in PERMISSIONS.
+pub fn parse_permission(name: &str) -> Option<Permissions> {
+    PERMISSIONS
+        .iter()
+        .find(|(_name, _)| _name.eq_ignore_ascii_case(name))
+        .map(|(_, perm)| *perm)
+}
+
+// permission_names lists the names of the permissions in PERMISSIONS that are set.
+pub fn permission_names(perms: Permissions) -> Vec<&'static str> {
+    PERMISSIONS
+        .iter()
+        .filter(|(_, perm)| perms.contains(*perm))
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+// find_room gets the room a channel is part of.
+pub fn find_room(serving: &Serving, channel_id: ChannelId) -> Option<&Room> {
+    serving.rooms.iter().find(|room| {
+        room.voice_ids.contains(&channel_id) || room.text_ids.contains(&channel_id)
+    })
+}
+
+// replace_room saves changes made to a room back into the guild's rooms. The room is found by its
+// ID. Returns false if it isn't there anymore.
+pub fn replace_room(serving: &mut Serving, room: &Room) -> bool {
+    match same_room(serving, room) {
+        Some(existing) => {
+            *existing = room.clone();
+            true
+        }
+        None => false,
+    }
+}
+
+// same_room finds a room in the guild's rooms by its ID.
+pub fn same_room<'a>(serving: &'a mut Serving, room: &Room) -> Option<&'a mut Room> {
+    serving.rooms.iter_mut().find(|existing| existing.id == room.id)
+}
+
+// link_category links a category so each of its voice channels pairs with the text channel in it
+// named after the voice channel (see text_name). channels are every channel of the guild, the
+// ones already in the category are paired right away. Returns how many rooms were made.
+pub fn link_category(
+    serving: &mut Serving,
+    category: &ChannelCategory,
+    pattern: Option<String>,
+    channels: &[GuildChannel],
+) -> usize {
+    match serving.categories.iter_mut().find(|link| link.category_id == category.id) {
+        Some(link) => link.pattern = pattern,
+        None => serving.categories.push(CategoryLink {
+            category_id: category.id,
+            pattern,
+        }),
+    }
+
+    pair_category(serving, category.id, channels)
+}
+
+// pair_category makes a room for every voice channel of a linked category that has a matching
+// text channel, as long as neither of them are linked yet. It's ran again whenever a channel is
+// created in the category. Returns how many rooms were made.
+pub fn pair_category(
+    serving: &mut Serving,
+    category_id: ChannelId,
+    channels: &[GuildChannel],
+) -> usize {
+    let pattern = match serving.categories.iter().find(|link| link.category_id == category_id) {
+        Some(link) => link.pattern.clone().unwrap_or_else(|| DEFAULT_PATTERN.to_string()),
+        None => return 0,
+    };
+
+    let in_category: Vec<&GuildChannel> = channels
+        .iter()
+        .filter(|channel| channel.category_id == Some(category_id))
+        .collect();
+    let mut paired = 0;
+
+    for voice in in_category.iter().filter(|c| c.kind == ChannelType::Voice) {
+        let name = text_name(&pattern, &voice.name);
+        let text = in_category.iter().find(|c| c.kind == ChannelType::Text && c.name == name);
+
+        if let Some(text) = text {
+            if is_linked(serving, voice.id) || is_linked(serving, text.id) {
+                continue;
+            }
+
+            let mut room = Room::new(vec![voice.id], vec![text.id]);
+            room.category_id = Some(category_id);
+            serving.rooms.push(room);
+            paired += 1;
+        }
+    }
+    paired
+}
+
+// text_name is the name of the text channel a voice channel pairs with in a linked category. The
+// voice channel's name is formatted the way Discord formats text channel names, "Gaming 1" becomes
+// "gaming-1".
+pub fn text_name(pattern: &str, voice_name: &str) -> String {
+    let name = voice_name.split_whitespace().collect::<Vec<&str>>().join("-");
+    pattern.replace("{name}", &name).to_lowercase()
+}
+
+// is_linked checks if a channel is part of any room.
+fn is_linked(serving: &Serving, channel_id: ChannelId) -> bool {
+    serving.rooms.iter().any(|room| {
+        room.voice_ids.contains(&channel_id) || room.text_ids.contains(&channel_id)
+    })
+}
+
+// unlink removes a channel from the room it's part of. Once a room has no voice channels or no
+// text channels left the room is removed, auto-rooms only need a voice channel. Unlinking a linked
+// category removes every room that was made for it, unlinking a hub stops it from making rooms.
+// Returns the rooms that were removed, or None if the channel isn't linked.
+pub fn unlink(serving: &mut Serving, channel_id: ChannelId) -> Option<Vec<Room>> {
+    if serving.hubs.contains(&channel_id) {
+        serving.hubs.retain(|id| *id != channel_id);
+        return Some(Vec::new());
+    }
+
+    if serving.categories.iter().any(|link| link.category_id == channel_id) {
+        serving.categories.retain(|link| link.category_id != channel_id);
+        let (removed, kept) = std::mem::take(&mut serving.rooms)
+            .into_iter()
+            .partition(|room| room.category_id == Some(channel_id));
+        serving.rooms = kept;
+        return Some(removed);
+    }
+
+    let i = serving.rooms.iter().position(|room| {
+        room.voice_ids.contains(&channel_id)
+            || room.text_ids.contains(&channel_id)
+            || room.archived_ids.contains(&channel_id)
+    })?;
+
+    let room = &mut serving.rooms[i];
+    room.voice_ids.retain(|id| *id != channel_id);
+    room.text_ids.retain(|id| *id != channel_id);
+    room.archived_ids.retain(|id| *id != channel_id);
+    let no_texts = room.text_ids.is_empty() && !matches!(room.kind, RoomKind::Auto { .. });
+    if room.voice_ids.is_empty() || no_texts {
+        return Some(vec![serving.rooms.remove(i)]);
+    }
+    Some(Vec::new())
+}
+
+// Target is a user or role being added to or removed from a room's list.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    User(UserId),
+    Role(RoleId),
+}
+
+impl Target {
+    // kind is the kind of overwrite the user or role has on a text-channel.
+    pub fn kind(self) -> PermissionOverwriteType {
+        match self {
+            Target::User(user_id) => PermissionOverwriteType::Member(user_id),
+            Target::Role(role_id) => PermissionOverwriteType::Role(role_id),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::User(user_id) => write!(f, "<@{}>", user_id),
+            Target::Role(role_id) => write!(f, "<@&{}>", role_id),
+        }
+    }
+}
+
+// list_add adds a user or role to a list. Returns false if they were already on it.
+pub fn list_add(list: &mut MemberList, target: Target) -> bool {
+    match target {
+        Target::User(user_id) if !list.users.contains(&user_id) => list.users.push(user_id),
+        Target::Role(role_id) if !list.roles.contains(&role_id) => list.roles.push(role_id),
+        _ => return false,
+    }
+    true
+}
+
+// list_remove removes a user or role from a list. Returns false if they weren't on it.
+pub fn list_remove(list: &mut MemberList, target: Target) -> bool {
+    let before = list.users.len() + list.roles.len();
+    match target {
+        Target::User(user_id) => list.users.retain(|id| *id != user_id),
+        Target::Role(role_id) => list.roles.retain(|id| *id != role_id),
+    }
+    before != list.users.len() + list.roles.len()
+}
+
+// list_names mentions everyone on a list.
+pub fn list_names(list: &MemberList) -> Vec<String> {
+    let users = list.users.iter().map(|id| Target::User(*id));
+    let roles = list.roles.iter().map(|id| Target::Role(*id));
+    users.chain(roles).map(|target| target.to_string()).collect()
+}
@@ -0,0 +1,132 @@
+use crate::bot::core::{self, SyncSummary};
+use crate::bot::util;
+use log::info;
+use serenity::client::Context;
+use serenity::model::prelude::*;
+use serenity::prelude::TypeMapKey;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::delay_for;
+
+// How long a room waits for more voice state updates before it's reviewed, so everyone that joins
+// or leaves around the same time is synced at once.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+// Reviews are the rooms waiting for or in the middle of a review, by the room's ID.
+pub struct Reviews;
+
+impl TypeMapKey for Reviews {
+    type Value = Arc<Mutex<Queue>>;
+}
+
+#[derive(Debug, Default)]
+pub struct Queue {
+    // How many reviews of a room were asked for since the last one started.
+    pending: HashMap<u64, usize>,
+    // Held while a room is reviewed so it's only ever reviewed one at a time, otherwise two
+    // reviews could both make an auto-room's text-channel. Hubs hold theirs while making a room.
+    locks: HashMap<Key, Arc<AsyncMutex<()>>>,
+}
+
+// Key is what a lock in the Queue is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Key {
+    Room(u64),
+    Hub(ChannelId),
+}
+
+// queue gets the review Queue out of the client's data.
+async fn queue(ctx: &Context) -> Arc<Mutex<Queue>> {
+    let data = ctx.data.read().await;
+    data.get::<Reviews>().expect("Failed to retrieve reviews").clone()
+}
+
+// exclusive does work once nothing else holds the lock of key.
+async fn exclusive<F: Future>(ctx: &Context, key: Key, work: F) -> F::Output {
+    let queue = queue(ctx).await;
+    let lock;
+    {
+        let mut queue = queue.lock().unwrap();
+        let locks = &mut queue.locks;
+        lock = locks.entry(key).or_insert_with(|| Arc::new(AsyncMutex::new(()))).clone();
+    }
+
+    let output;
+    {
+        let _working = lock.lock().await;
+        output = work.await;
+    }
+
+    // The lock is dropped once no one else is waiting on it.
+    let mut queue = queue.lock().unwrap();
+    if Arc::strong_count(&lock) == 2 {
+        queue.locks.remove(&key);
+    }
+    output
+}
+
+// schedule asks for a room to be reviewed. Asking while the room is already waiting for or in the
+// middle of a review is coalesced into its next review.
+pub async fn schedule(ctx: &Context, guild_id: GuildId, room_id: u64) {
+    let queue = queue(ctx).await;
+
+    {
+        let mut queue = queue.lock().unwrap();
+        if let Some(requests) = queue.pending.get_mut(&room_id) {
+            *requests += 1;
+            return;
+        }
+        queue.pending.insert(room_id, 1);
+    }
+
+    tokio::spawn(run(ctx.clone(), queue, guild_id, room_id));
+}
+
+// review reviews a room right away, waiting for the review that's in progress if there is one.
+// Rehearsing only works out what the review would change.
+pub async fn review(ctx: &Context, guild_id: GuildId, room_id: u64, rehearse: bool) -> SyncSummary {
+    let work = async {
+        // The room is fetched again since it might've changed or been removed in the meantime.
+        let room = util::store(ctx)
+            .await
+            .find(guild_id)
+            .and_then(|serving| serving.rooms.into_iter().find(|room| room.id == room_id));
+        match room {
+            Some(room) => core::review_room(ctx, guild_id, &room, rehearse).await,
+            None => SyncSummary::default(),
+        }
+    };
+    exclusive(ctx, Key::Room(room_id), work).await
+}
+
+// create_room gives someone that joined a hub a room of their own. A hub makes one room at a time
+// so everyone that joins it at once gets theirs saved.
+pub async fn create_room(ctx: &Context, guild_id: GuildId, hub_id: ChannelId, user_id: UserId) {
+    let work = core::create_temporary_room(ctx, guild_id, hub_id, user_id);
+    exclusive(ctx, Key::Hub(hub_id), work).await
+}
+
+// run keeps reviewing a room until no more reviews were asked for during the last one.
+async fn run(ctx: Context, queue: Arc<Mutex<Queue>>, guild_id: GuildId, room_id: u64) {
+    loop {
+        delay_for(DEBOUNCE).await;
+
+        let requests = queue.lock().unwrap().pending.insert(room_id, 0).unwrap_or(0);
+        if requests > 1 {
+            info!("Coalesced {} reviews of room {} into one", requests, room_id);
+        }
+
+        review(&ctx, guild_id, room_id, false).await;
+
+        {
+            let mut queue = queue.lock().unwrap();
+            if queue.pending.get(&room_id) == Some(&0) {
+                queue.pending.remove(&room_id);
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use crate::bot::{commands, core, rooms, util};
+use crate::config::Serving;
+use log::{info, warn};
+use serde_json::{json, Value};
+use serenity::client::Context;
+use serenity::model::prelude::*;
+use serenity::prelude::TypeMapKey;
+use std::sync::Arc;
+
+// Serenity 0.9 doesn't know about application commands, so they're registered and answered over
+// Discord's HTTP API and their interactions come in as unknown gateway events. The commands
+// mirror add, remove and list and link rooms with the same rules in bot::rooms.
+
+const API: &'static str = "https://discord.com/api/v8";
+
+// The interaction type of a slash command and the response type that replies with a message.
+const APPLICATION_COMMAND: u64 = 2;
+const CHANNEL_MESSAGE: u64 = 4;
+// Replies are only shown to whoever used the command.
+const EPHEMERAL: u64 = 64;
+
+// Slash is what's needed to talk to Discord's application command API.
+pub struct Slash {
+    client: reqwest::Client,
+    token: String,
+    application_id: UserId,
+}
+
+impl TypeMapKey for Slash {
+    type Value = Arc<Slash>;
+}
+
+impl Slash {
+    pub fn new(token: &str, application_id: UserId) -> Slash {
+        Slash {
+            client: reqwest::Client::new(),
+            token: token.trim_start_matches("Bot ").to_string(),
+            application_id,
+        }
+    }
+
+    // register replaces the slash commands of a guild with add, remove and list.
+    pub async fn register(&self, guild_id: GuildId) {
+        let url = format!(
+            "{}/applications/{}/guilds/{}/commands",
+            API, self.application_id, guild_id
+        );
+        let res = self
+            .client
+            .put(&url)
+            .header("Authorization", format!("Bot {}", self.token))
+            .json(&definitions())
+            .send()
+            .await
+            .and_then(|res| res.error_for_status());
+
+        if let Err(why) = res {
+            warn!("Failed to register the slash commands of {} because\n{}", guild_id, why);
+        }
+    }
+
+    // reply answers an interaction with a message only the user that used the command can see.
+    async fn reply(&self, id: &str, token: &str, content: &str) {
+        let url = format!("{}/interactions/{}/{}/callback", API, id, token);
+        let body = json!({
+            "type": CHANNEL_MESSAGE,
+            "data": {
+                "content": content,
+                "flags": EPHEMERAL,
+                "allowed_mentions": { "parse": [] },
+            },
+        });
+        let res = self
+            .client
+            .post(&url)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status());
+
+        if let Err(why) = res {
+            warn!("Failed to reply to interaction {} because\n{}", id, why);
+        }
+    }
+}
+
+// definitions are the slash commands every guild gets. Channel options only offer channels of the
+// listed types, 0 is a text channel and 2 a voice channel.
+fn definitions() -> Value {
+    json!([
+        {
+            "name": "add",
+            "description": "Link a voice channel and a text channel together",
+            "options": [
+                {
+                    "type": 7,
+                    "name": "voice",
+                    "description": "The voice channel",
+                    "required": true,
+                    "channel_types": [2],
+                },
+                {
+                    "type": 7,
+                    "name": "text",
+                    "description": "The text channel",
+                    "required": true,
+                    "channel_types": [0],
+                },
+            ],
+        },
+        {
+            "name": "remove",
+            "description": "Unlink a channel from its room",
+            "options": [
+                {
+                    "type": 7,
+                    "name": "channel",
+                    "description": "The voice channel or text channel",
+                    "required": true,
+                    "channel_types": [0, 2],
+                },
+            ],
+        },
+        {
+            "name": "list",
+            "description": "List the rooms of this server",
+        },
+    ])
+}
+
+// slash gets Slash out of the client's data, there's none if the application couldn't be fetched.
+async fn slash(ctx: &Context) -> Option<Arc<Slash>> {
+    let data = ctx.data.read().await;
+    data.get::<Slash>().cloned()
+}
+
+// register registers the slash commands of a guild.
+pub async fn register(ctx: &Context, guild_id: GuildId) {
+    if let Some(slash) = slash(ctx).await {
+        slash.register(guild_id).await;
+    }
+}
+
+// interaction handles the raw INTERACTION_CREATE event of a slash command.
+pub async fn interaction(ctx: &Context, raw: &Value) {
+    if raw["type"].as_u64() != Some(APPLICATION_COMMAND) {
+        return;
+    }
+
+    let (id, token) = match (raw["id"].as_str(), raw["token"].as_str()) {
+        (Some(id), Some(token)) => (id, token),
+        _ => return,
+    };
+
+    let res = match raw["guild_id"].as_str().and_then(|id| id.parse::<u64>().ok()) {
+        Some(guild_id) => run(ctx, GuildId(guild_id), raw).await,
+        None => "Please use this command in a guild.".to_string(),
+    };
+
+    if let Some(slash) = slash(ctx).await {
+        slash.reply(id, token, &res).await;
+    }
+}
+
+// run runs a slash command and returns what to reply with.
+async fn run(ctx: &Context, guild_id: GuildId, raw: &Value) -> String {
+    let name = raw["data"]["name"].as_str().unwrap_or_default();
+    let user = raw["member"]["user"]["username"].as_str().unwrap_or_default();
+
+    // Like auth, add and remove need Manage Channels or Administrator. The member's permissions
+    // in an interaction already include the overwrites of the channel it was used in.
+    if name != "list" {
+        let perms = raw["member"]["permissions"]
+            .as_str()
+            .and_then(|perms| perms.parse::<u64>().ok())
+            .map_or(Permissions::empty(), Permissions::from_bits_truncate);
+
+        if !commands::check_perms(&perms) {
+            warn!(
+                "
+Failed Slash Command Execution
+ * User: {}
+ * Command: /{}
+ * Guild: {}
+ * Reason: Missing permissions",
+                user, name, guild_id,
+            );
+            return "You miss the required permissions to run this command.".to_string();
+        }
+
+        info!(
+            "
+Slash Command Execution
+ * User: {}
+ * Command: /{}
+ * Guild: {}",
+            user, name, guild_id,
+        );
+    }
+
+    let store = util::store(ctx).await;
+    let mut serving;
+    match store.get(guild_id) {
+        Ok(None) if name == "list" => return "This server has no rooms".to_string(),
+        Ok(_serving) => serving = _serving.unwrap_or_else(|| Serving::new(guild_id)),
+        Err(why) => {
+            warn!("Failed to get the rooms of {} because\n{}", guild_id, why);
+            return "Failed to load this server's rooms, please try again later.".to_string();
+        }
+    }
+
+    match name {
+        "add" => {
+            let voice;
+            match option_channel(ctx, raw, "voice").await {
+                Some(_voice) => voice = _voice,
+                None => return rooms::LinkError::NotVoice.to_string(),
+            }
+            let text;
+            match option_channel(ctx, raw, "text").await {
+                Some(_text) => text = _text,
+                None => return rooms::LinkError::NotText.to_string(),
+            }
+
+            if let Err(why) = rooms::link(&mut serving, &voice, &text) {
+                return why.to_string();
+            }
+            if let Err(why) = store.put(&serving) {
+                warn!("Failed to save the rooms of {} because\n{}", guild_id, why);
+                return format!("Failed to save the room, {}", why);
+            }
+            format!("Linked <#{}> with {}", text.id, voice.name)
+        }
+        "remove" => {
+            let channel_id;
+            match option_id(raw, "channel") {
+                Some(_channel_id) => channel_id = ChannelId(_channel_id),
+                None => return "Please provide a channel.".to_string(),
+            }
+
+            let removed;
+            match rooms::unlink(&mut serving, channel_id) {
+                Some(_removed) => removed = _removed,
+                None => return format!("<#{}> isn't linked with anything.", channel_id),
+            }
+            if let Err(why) = store.put(&serving) {
+                warn!("Failed to save the rooms of {} because\n{}", guild_id, why);
+                return format!("Failed to save the rooms, {}", why);
+            }
+            core::forget_rooms(ctx, guild_id, &removed).await;
+            format!("Unlinked <#{}>", channel_id)
+        }
+        "list" => commands::describe(ctx, &serving).await,
+        _ => format!("Unknown command /{}", name),
+    }
+}
+
+// option_id gets the ID given to an option of a slash command.
+fn option_id(raw: &Value, name: &str) -> Option<u64> {
+    raw["data"]["options"]
+        .as_array()?
+        .iter()
+        .find(|option| option["name"].as_str() == Some(name))?["value"]
+        .as_str()?
+        .parse::<u64>()
+        .ok()
+}
+
+// option_channel gets the channel given to a channel option of a slash command.
+async fn option_channel(ctx: &Context, raw: &Value, name: &str) -> Option<GuildChannel> {
+    let channel_id = ChannelId(option_id(raw, name)?);
+    match channel_id.to_channel(ctx).await {
+        Ok(Channel::Guild(channel)) => Some(channel),
+        _ => None,
+    }
+}
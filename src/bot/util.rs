@@ -1,8 +1,138 @@
-use crate::config::Room;
+use crate::bot::core::SyncSummary;
+use crate::bot::holders::RoleHolders;
+use crate::bot::metrics::Metrics;
+use crate::bot::planner;
+use crate::bot::rooms::Target;
+use crate::bot::voice::VoiceIndex;
+use crate::config::{Config, MemberList, PermissionTemplate, Room, Serving};
+use crate::store::{RoomStore, Store};
 use log::{info, warn};
 use serenity::client::Context;
 use serenity::framework::standard::Args;
 use serenity::model::prelude::*;
+use serenity::prelude::TypeMapKey;
+use serenity::utils::{parse_role, parse_username};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+// store gets the RoomStore out of the client's data.
+pub async fn store(ctx: &Context) -> Arc<dyn RoomStore> {
+    let data = ctx.data.read().await;
+    data.get::<Store>().expect("Failed to retrieve store").clone()
+}
+
+// serving gets the rooms of the guild a command was used in, or a Serving without any if it has
+// none yet. If they couldn't be loaded the user is told and None is returned, so a command never
+// saves an empty Serving over rooms that are still there.
+pub async fn serving(ctx: &Context, msg: &Message, store: &dyn RoomStore) -> Option<Serving> {
+    let guild_id = msg.guild_id?;
+    match store.get(guild_id) {
+        Ok(serving) => Some(serving.unwrap_or_else(|| Serving::new(guild_id))),
+        Err(why) => {
+            warn!("Failed to get the rooms of {} because\n{}", guild_id, why);
+            let res = "Failed to load this server's rooms, please try again later.".to_string();
+            bad(ctx, msg).await;
+            respond(ctx, msg, &res).await;
+            None
+        }
+    }
+}
+
+// metrics gets the cache Metrics out of the client's data.
+pub async fn metrics(ctx: &Context) -> Arc<Metrics> {
+    let data = ctx.data.read().await;
+    data.get::<Metrics>().expect("Failed to retrieve metrics").clone()
+}
+
+// role_holders gets the RoleHolders out of the client's data.
+pub async fn role_holders(ctx: &Context) -> Arc<Mutex<RoleHolders>> {
+    let data = ctx.data.read().await;
+    data.get::<RoleHolders>().expect("Failed to retrieve role holders").clone()
+}
+
+// voice_index gets the VoiceIndex out of the client's data.
+pub async fn voice_index(ctx: &Context) -> Arc<RwLock<VoiceIndex>> {
+    let data = ctx.data.read().await;
+    data.get::<VoiceIndex>().expect("Failed to retrieve voice index").clone()
+}
+
+// dry_run checks if syncing the rooms of a guild should only report what it would change, either
+// because of the config or the guild. The channel the guild wants the reports in comes with it.
+pub async fn dry_run(ctx: &Context, guild_id: GuildId) -> (bool, Option<ChannelId>) {
+    let everywhere;
+    {
+        let data = ctx.data.read().await;
+        everywhere = data.get::<Config>().map_or(false, |config| config.dry_run);
+    }
+
+    match store(ctx).await.find(guild_id) {
+        Some(serving) => (everywhere || serving.dry_run, serving.report_channel),
+        None => (everywhere, None),
+    }
+}
+
+// Reports are the last dry-run report of each room, by the room's ID or a hub's for the rooms it'd
+// make, so the same report isn't posted again on every voice state update.
+pub struct Reports;
+
+impl TypeMapKey for Reports {
+    type Value = Arc<Mutex<HashMap<u64, String>>>;
+}
+
+// report_dry_run reports what a dry run of a room would've changed in the log, and in the report
+// channel if there is one. Reports are only made when something would've changed that wasn't
+// reported last time.
+pub async fn report_dry_run(
+    ctx: &Context,
+    report_channel: Option<ChannelId>,
+    key: u64,
+    room: &str,
+    summary: &SyncSummary,
+) {
+    let mut report = format!(
+        "Dry run of {}\n * Would grant: {}\n * Would revoke: {}",
+        room,
+        mentions(&summary.granted),
+        mentions(&summary.revoked),
+    );
+    for action in summary.actions.iter() {
+        report += &format!("\n * {}", action);
+    }
+
+    let reports;
+    {
+        let data = ctx.data.read().await;
+        reports = data.get::<Reports>().expect("Failed to retrieve reports").clone();
+    }
+    {
+        let mut reports = reports.lock().unwrap();
+        if reports.get(&key) == Some(&report) {
+            return;
+        }
+        reports.insert(key, report.clone());
+    }
+
+    let changes = !summary.granted.is_empty() || !summary.revoked.is_empty();
+    if !changes && summary.actions.is_empty() {
+        return;
+    }
+
+    info!("{}", report);
+    if let Some(channel_id) = report_channel {
+        if let Err(why) = channel_id.say(ctx, &report).await {
+            warn!("Failed to report a dry run in {} because\n{}", channel_id, why);
+        }
+    }
+}
+
+// mentions mentions a list of users, or says no one if it's empty.
+pub fn mentions(ids: &[UserId]) -> String {
+    if ids.is_empty() {
+        return "no one".to_string();
+    }
+    let mentions: Vec<String> = ids.iter().map(|id| format!("<@{}>", id)).collect();
+    mentions.join(", ")
+}
 
 // Get the channels a user might be talking about in a message.
 // args can be [<#channel id>, channel id] or reversed
@@ -42,6 +172,42 @@ pub async fn parse_channels(ctx: &Context, args: &mut Args) -> Option<(Channel,
     return Some((voice, text));
 }
 
+// parse_channel_id gets the ID out of a #channel mention or a plain channel ID.
+pub fn parse_channel_id(arg: &str) -> Option<ChannelId> {
+    if arg.starts_with("<#") {
+        if let Some(extract) = arg.get(2..arg.len() - 1) {
+            if let Ok(channel_id) = extract.parse::<u64>() {
+                return Some(ChannelId(channel_id));
+            }
+        }
+    } else if let Ok(channel_id) = arg.parse::<u64>() {
+        return Some(ChannelId(channel_id));
+    }
+    None
+}
+
+// parse_target gets a user or role out of a mention or a plain ID. Plain IDs are roles if the
+// guild has a role by that ID, otherwise they're users.
+pub async fn parse_target(ctx: &Context, guild_id: GuildId, arg: &str) -> Option<Target> {
+    if let Some(role_id) = parse_role(arg) {
+        return Some(Target::Role(RoleId(role_id)));
+    }
+    if let Some(user_id) = parse_username(arg) {
+        return Some(Target::User(UserId(user_id)));
+    }
+
+    let id = arg.parse::<u64>().ok()?;
+    let is_role = match ctx.cache.guild(guild_id).await {
+        Some(guild) => guild.roles.contains_key(&RoleId(id)),
+        None => false,
+    };
+    if is_role {
+        Some(Target::Role(RoleId(id)))
+    } else {
+        Some(Target::User(UserId(id)))
+    }
+}
+
 // respond intakes a body and responds to a user.
 pub async fn respond(ctx: &Context, msg: &Message, body: &String) {
     let res = format!("<@{}>, {}", msg.author.id, body);
@@ -74,88 +240,225 @@ async fn react(ctx: &Context, msg: &Message, unicode: String) {
     }
 }
 
-// get_channels will get the text-channel and voice-channel of a Room. If one of them failed then
-// None will be returned.
-pub async fn get_channels(ctx: &Context, room: &Room) -> Option<(GuildChannel, GuildChannel)> {
-    let mut channel_rw;
+// get_channels will get the voice-channels and text-channels of a Room. Channels that failed are
+// left out.
+pub async fn get_channels(ctx: &Context, room: &Room) -> (Vec<GuildChannel>, Vec<GuildChannel>) {
+    let voice_channels = fetch_channels(ctx, &room.voice_ids).await;
+    let text_channels = fetch_channels(ctx, &room.text_ids).await;
+    (voice_channels, text_channels)
+}
 
-    if let Ok(_channel) = room.voice_id.to_channel(ctx).await {
-        if let Some(_guild_rw) = _channel.guild() {
-            channel_rw = _guild_rw;
-        } else {
-            return None;
+// fetch_channels gets channels from the cache, only the ones missing from it are fetched over HTTP.
+async fn fetch_channels(ctx: &Context, ids: &[ChannelId]) -> Vec<GuildChannel> {
+    let metrics = metrics(ctx).await;
+    let mut channels = Vec::new();
+    for id in ids.iter() {
+        if let Some(channel) = ctx.cache.guild_channel(*id).await {
+            metrics.channels.hit();
+            channels.push(channel);
+            continue;
+        }
+
+        metrics.channels.miss();
+        match id.to_channel(ctx).await {
+            Ok(_channel) => {
+                if let Some(_guild_rw) = _channel.guild() {
+                    channels.push(_guild_rw);
+                }
+            }
+            Err(why) => warn!("Failed to get {} because\n{}", id, why),
         }
-    } else {
-        return None;
     }
+    channels
+}
 
-    let voice_channel = channel_rw;
+// grant_access gives people of a voice channel access to the linked text-channel with the
+// permissions of the room's template. Returns true if the overwrite was created.
+pub async fn grant_access(
+    ctx: &Context,
+    text: &GuildChannel,
+    member_id: UserId,
+    template: &PermissionTemplate,
+) -> bool {
+    let overwrite = template.overwrite(PermissionOverwriteType::Member(member_id));
 
-    if let Ok(_channel) = room.text_id.to_channel(ctx).await {
-        if let Some(_guild_rw) = _channel.guild() {
-            channel_rw = _guild_rw;
-        } else {
-            return None;
-        }
-    } else {
-        return None;
+    if manage_access(ctx, text, Some(overwrite), member_id).await {
+        info!("Granted access for {} in #{}", member_id, text.name);
+        return true;
     }
+    false
+}
 
-    let text_channel = channel_rw;
-    Some((voice_channel, text_channel))
+// revoke_access revokes people outside of a voice channel from the linked text-channel. Their
+// overwrite is deleted rather than replaced with a deny, so everything the template gave them is
+// cleared and they fall back to whatever their roles allow. Returns true if the overwrite was
+// deleted.
+pub async fn revoke_access(ctx: &Context, text: &GuildChannel, member_id: UserId) -> bool {
+    if manage_access(ctx, text, None, member_id).await {
+        info!("Revoked access for {} in #{}", member_id, text.name);
+        return true;
+    }
+    false
 }
 
-// grant_access gives people of a voice channel access to the linked text-channel
-pub async fn grant_access(ctx: &Context, text: &GuildChannel, member_id: UserId) {
-    let overwrite = PermissionOverwrite {
-        allow: Permissions::READ_MESSAGES,
-        deny: Permissions::empty(),
-        kind: PermissionOverwriteType::Member(member_id),
-    };
+// grant_role gives people of a voice channel the role of a room in role access mode. Returns true
+// if the role was added.
+pub async fn grant_role(
+    ctx: &Context,
+    guild_id: GuildId,
+    role_id: RoleId,
+    member_id: UserId,
+) -> bool {
+    if is_bot(ctx, member_id).await {
+        return false;
+    }
 
-    if manage_access(ctx, text, &overwrite, member_id).await {
-        info!("Granted access for {} in #{}", member_id, text.name);
+    let result = ctx
+        .http
+        .add_member_role(*guild_id.as_u64(), *member_id.as_u64(), *role_id.as_u64())
+        .await;
+
+    if let Err(why) = result {
+        warn!("Failed to give {} the role {} because\n{}", member_id, role_id, why);
+        return false;
     }
+    info!("Gave {} the role {}", member_id, role_id);
+    true
 }
 
-// revoke_access revokes people outside of a voice channel from the linked text-channel
-pub async fn revoke_access(ctx: &Context, text: &GuildChannel, member_id: UserId) {
-    let overwrite = PermissionOverwrite {
-        allow: Permissions::empty(),
-        deny: Permissions::READ_MESSAGES,
-        kind: PermissionOverwriteType::Member(member_id),
-    };
+// revoke_role takes the role of a room in role access mode away from people outside of its voice
+// channels. Returns true if the role was removed.
+pub async fn revoke_role(
+    ctx: &Context,
+    guild_id: GuildId,
+    role_id: RoleId,
+    member_id: UserId,
+) -> bool {
+    if is_bot(ctx, member_id).await {
+        return false;
+    }
 
-    if manage_access(ctx, text, &overwrite, member_id).await {
-        info!("Revoked access for {} in #{}", member_id, text.name);
+    let result = ctx
+        .http
+        .remove_member_role(*guild_id.as_u64(), *member_id.as_u64(), *role_id.as_u64())
+        .await;
+
+    if let Err(why) = result {
+        warn!("Failed to take the role {} from {} because\n{}", role_id, member_id, why);
+        return false;
     }
+    info!("Took the role {} from {}", role_id, member_id);
+    true
 }
 
-async fn manage_access(
+// grant_role_access lets a room's role into one of its text-channels with the permissions of the
+// room's template. Returns true if the overwrite was created.
+pub async fn grant_role_access(
     ctx: &Context,
     text: &GuildChannel,
-    overwrite: &PermissionOverwrite,
-    member_id: UserId,
+    role_id: RoleId,
+    template: &PermissionTemplate,
 ) -> bool {
-    // ignore bots
-    let is_bot;
+    let overwrite = template.overwrite(PermissionOverwriteType::Role(role_id));
 
-    if let Ok(user) = member_id.to_user(&ctx).await {
-        is_bot = user.bot;
-    } else {
-        // return false for safety
+    if let Err(why) = text.create_permission(ctx, &overwrite).await {
+        warn!("Failed to let {} into #{} because\n{}", role_id, text.name, why);
         return false;
     }
+    info!("Granted access for {} in #{}", role_id, text.name);
+    true
+}
+
+// deny_access keeps a user or role on a room's denylist out of one of its text-channels. Returns
+// true if the overwrite was created.
+pub async fn deny_access(ctx: &Context, text: &GuildChannel, target: Target) -> bool {
+    let overwrite = planner::deny_overwrite(target);
 
-    if is_bot {
+    if let Err(why) = text.create_permission(ctx, &overwrite).await {
+        warn!("Failed to keep {} out of #{} because\n{}", target, text.name, why);
         return false;
     }
+    info!("Denied access for {} in #{}", target, text.name);
+    true
+}
+
+// stale_overwrites finds the deny-only overwrites older versions left behind when revoking access.
+// A moderator's block looks just the same, which is why cleanup asks before purging them. The ones
+// of users on the room's denylist are the bot's and are left out.
+pub fn stale_overwrites(text: &GuildChannel, denylist: &MemberList) -> Vec<UserId> {
+    let mut stale = Vec::new();
+    for perm in text.permission_overwrites.iter() {
+        if let PermissionOverwriteType::Member(member_id) = perm.kind {
+            if !perm.allow.is_empty() || perm.deny != Permissions::READ_MESSAGES {
+                continue;
+            }
+            if !denylist.users.contains(&member_id) {
+                stale.push(member_id);
+            }
+        }
+    }
+    stale
+}
+
+// purge_stale_overwrites deletes the overwrites of stale_overwrites. Returns how many were
+// deleted.
+pub async fn purge_stale_overwrites(ctx: &Context, text: &GuildChannel, stale: &[UserId]) -> usize {
+    let mut purged = 0;
+
+    for member_id in stale.iter() {
+        let kind = PermissionOverwriteType::Member(*member_id);
+        if let Err(why) = text.delete_permission(ctx, kind).await {
+            warn!("Failed to purge {}'s overwrite because\n{}", member_id, why);
+        } else {
+            purged += 1;
+        }
+    }
+
+    if purged > 0 {
+        info!("Purged {} stale overwrites in #{}", purged, text.name);
+    }
+    purged
+}
 
+// manage_access creates the overwrite of a member, or deletes it if there is no overwrite.
+async fn manage_access(
+    ctx: &Context,
+    text: &GuildChannel,
+    overwrite: Option<PermissionOverwrite>,
+    member_id: UserId,
+) -> bool {
+    if is_bot(ctx, member_id).await {
+        return false;
+    }
 
-    if let Err(why) = text.create_permission(ctx, overwrite).await {
-        warn!("Failed to grant {} access because\n{}", member_id, why);
+    let result = match overwrite {
+        Some(overwrite) => text.create_permission(ctx, &overwrite).await,
+        None => {
+            let kind = PermissionOverwriteType::Member(member_id);
+            text.delete_permission(ctx, kind).await
+        }
+    };
+
+    if let Err(why) = result {
+        warn!("Failed to manage {}'s access because\n{}", member_id, why);
         return false;
     } else {
         return true;
     }
 }
+
+// is_bot checks if a user is a bot, bots are never given or revoked access. Users that can't be
+// fetched are treated as bots for safety.
+async fn is_bot(ctx: &Context, member_id: UserId) -> bool {
+    let metrics = metrics(ctx).await;
+    if let Some(user) = ctx.cache.user(member_id).await {
+        metrics.users.hit();
+        return user.bot;
+    }
+
+    metrics.users.miss();
+    match member_id.to_user(&ctx).await {
+        Ok(user) => user.bot,
+        Err(_) => true,
+    }
+}
@@ -0,0 +1,133 @@
+use serenity::model::prelude::*;
+use serenity::prelude::TypeMapKey;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+// VoiceIndex is who is in which voice channel, kept up to date by every voice state update. It's
+// where sync_room finds out who is in a room.
+#[derive(Debug, Default)]
+pub struct VoiceIndex {
+    channels: HashMap<ChannelId, HashSet<UserId>>,
+    users: HashMap<(GuildId, UserId), ChannelId>,
+}
+
+impl TypeMapKey for VoiceIndex {
+    type Value = Arc<RwLock<VoiceIndex>>;
+}
+
+impl VoiceIndex {
+    // update moves someone into the voice channel they joined, or out of voice if they left.
+    pub fn update(&mut self, guild_id: GuildId, user_id: UserId, channel_id: Option<ChannelId>) {
+        if let Some(old_id) = self.users.remove(&(guild_id, user_id)) {
+            if let Some(users) = self.channels.get_mut(&old_id) {
+                users.remove(&user_id);
+                if users.is_empty() {
+                    self.channels.remove(&old_id);
+                }
+            }
+        }
+
+        if let Some(channel_id) = channel_id {
+            self.users.insert((guild_id, user_id), channel_id);
+            self.channels.entry(channel_id).or_default().insert(user_id);
+        }
+    }
+
+    // seed replaces everything known about a guild with its voice states, such as the ones in the
+    // cache once it's ready. states are who is in which voice channel.
+    pub fn seed<I>(&mut self, guild_id: GuildId, states: I)
+    where
+        I: IntoIterator<Item = (UserId, Option<ChannelId>)>,
+    {
+        let stale: Vec<UserId> = self
+            .users
+            .keys()
+            .filter(|(id, _)| *id == guild_id)
+            .map(|(_, user_id)| *user_id)
+            .collect();
+        for user_id in stale {
+            self.update(guild_id, user_id, None);
+        }
+
+        for (user_id, channel_id) in states {
+            self.update(guild_id, user_id, channel_id);
+        }
+    }
+
+    // members gets everyone in a voice channel.
+    pub fn members(&self, channel_id: ChannelId) -> Vec<UserId> {
+        match self.channels.get(&channel_id) {
+            Some(users) => users.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUILD: GuildId = GuildId(1);
+    const OTHER_GUILD: GuildId = GuildId(2);
+    const LOBBY: ChannelId = ChannelId(10);
+    const GAMING: ChannelId = ChannelId(11);
+    const ELSEWHERE: ChannelId = ChannelId(20);
+
+    // members is who is in a voice channel, sorted so the tests don't depend on the order.
+    fn members(index: &VoiceIndex, channel_id: ChannelId) -> Vec<UserId> {
+        let mut members = index.members(channel_id);
+        members.sort();
+        members
+    }
+
+    #[test]
+    fn joining_puts_them_in_the_channel() {
+        let mut index = VoiceIndex::default();
+        index.update(GUILD, UserId(1), Some(LOBBY));
+        index.update(GUILD, UserId(2), Some(LOBBY));
+
+        assert_eq!(members(&index, LOBBY), vec![UserId(1), UserId(2)]);
+        assert!(members(&index, GAMING).is_empty());
+    }
+
+    #[test]
+    fn moving_takes_them_out_of_the_old_channel() {
+        let mut index = VoiceIndex::default();
+        index.update(GUILD, UserId(1), Some(LOBBY));
+        index.update(GUILD, UserId(1), Some(GAMING));
+
+        assert!(members(&index, LOBBY).is_empty());
+        assert_eq!(members(&index, GAMING), vec![UserId(1)]);
+    }
+
+    #[test]
+    fn leaving_takes_them_out_of_voice() {
+        let mut index = VoiceIndex::default();
+        index.update(GUILD, UserId(1), Some(LOBBY));
+        index.update(GUILD, UserId(2), Some(LOBBY));
+        index.update(GUILD, UserId(1), None);
+
+        assert_eq!(members(&index, LOBBY), vec![UserId(2)]);
+
+        index.update(GUILD, UserId(2), None);
+        assert!(index.channels.is_empty());
+        assert!(index.users.is_empty());
+    }
+
+    #[test]
+    fn seeding_replaces_what_was_known_of_the_guild() {
+        let mut index = VoiceIndex::default();
+        index.update(GUILD, UserId(1), Some(LOBBY));
+        index.update(GUILD, UserId(2), Some(LOBBY));
+        index.update(OTHER_GUILD, UserId(1), Some(ELSEWHERE));
+
+        // 1 moved and 2 left while the bot wasn't listening, 3 joined.
+        let states = vec![(UserId(1), Some(GAMING)), (UserId(3), Some(LOBBY))];
+        index.seed(GUILD, states);
+
+        assert_eq!(members(&index, LOBBY), vec![UserId(3)]);
+        assert_eq!(members(&index, GAMING), vec![UserId(1)]);
+        // Other guilds are left alone, even for the same user.
+        assert_eq!(members(&index, ELSEWHERE), vec![UserId(1)]);
+    }
+}
@@ -1,87 +1,509 @@
 use log::info;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_yaml;
-use serenity::model::id::GuildId;
+use serenity::model::channel::{PermissionOverwrite, PermissionOverwriteType};
+use serenity::model::id::{GuildId, RoleId, UserId};
+use serenity::model::permissions::Permissions;
 use serenity::model::prelude::ChannelId;
 use std::collections::BTreeMap;
 use std::env;
-use std::fs::File;
-use std::io::prelude::*;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, prelude::*};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 // For getting a custom config path
 const ENV_VAR: &'static str = "CONFIG_PATH";
+// For overriding the token and prefix of the config
+const TOKEN_VAR: &'static str = "ROOMS_TOKEN";
+const PREFIX_VAR: &'static str = "ROOMS_PREFIX";
 const DEFAULT_LOCATION: &'static str = "./config.yml";
+const DEFAULT_ROOMS_LOCATION: &'static str = "./rooms.yml";
+const DEFAULT_BACKUPS: usize = 3;
+
+// ConfigError is why the config.yml couldn't be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    // There's no config.yml at the path yet.
+    NotFound(String),
+    // The config.yml exists but couldn't be read or written.
+    Io(String, io::Error),
+    // The config.yml isn't valid YAML or is missing fields.
+    Parse {
+        path: String,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::NotFound(path) => write!(f, "{} doesn't exist", path),
+            ConfigError::Io(path, why) => write!(f, "Failed to access {} because\n{}", path, why),
+            ConfigError::Parse {
+                path,
+                line,
+                column,
+                message,
+            } => write!(
+                f,
+                "{} is invalid at line {}, column {}\n{}",
+                path, line, column, message
+            ),
+        }
+    }
+}
 
 // Serving represents a guild the bot is serving
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Serving {
     pub guild_id: GuildId,
     pub rooms: Vec<Room>,
+    // The command prefix of this guild, if it doesn't use the one in the config.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    // Categories whose voice channels are linked automatically.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub categories: Vec<CategoryLink>,
+    // Voice channels that give whoever joins them a temporary room of their own.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hubs: Vec<ChannelId>,
+    // How many seconds people keep access to a room's text channels after leaving its voice
+    // channels, in case they're only reconnecting.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub grace_period: u64,
+    // Syncing the rooms of this guild only reports what it would change rather than changing it.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub dry_run: bool,
+    // The text channel dry runs are reported in, besides the log.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub report_channel: Option<ChannelId>,
+}
+
+impl Serving {
+    // new creates a Serving for a guild that has no rooms yet.
+    pub fn new(guild_id: GuildId) -> Serving {
+        Serving {
+            guild_id,
+            rooms: Vec::new(),
+            prefix: None,
+            categories: Vec::new(),
+            hubs: Vec::new(),
+            grace_period: 0,
+            dry_run: false,
+            report_channel: None,
+        }
+    }
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
 }
 
+// Room is a group of voice channels and the text channels they reveal to the people in them.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Room {
-    pub voice_id: ChannelId,
-    pub text_id: ChannelId,
+    // What the bot knows the room by, its channels can change. Rooms saved by older versions get
+    // one when the bot starts, see store::identify.
+    #[serde(default)]
+    pub id: u64,
+    // Older versions only had one voice and one text channel per room stored as voice_id and
+    // text_id.
+    #[serde(alias = "voice_id", deserialize_with = "one_or_many")]
+    pub voice_ids: Vec<ChannelId>,
+    #[serde(alias = "text_id", deserialize_with = "one_or_many")]
+    pub text_ids: Vec<ChannelId>,
+    // The text channels an auto-room with archive set kept hidden once everyone left. The last
+    // one is brought back the next time someone joins.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub archived_ids: Vec<ChannelId>,
+    // The linked category this room was made for, if it wasn't linked by hand.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category_id: Option<ChannelId>,
+    #[serde(default, skip_serializing_if = "RoomKind::is_linked")]
+    pub kind: RoomKind,
+    #[serde(default, skip_serializing_if = "Access::is_overwrites")]
+    pub access: Access,
+    #[serde(default, skip_serializing_if = "PermissionTemplate::is_default")]
+    pub permissions: PermissionTemplate,
+    // Users and roles that can always see the text channels, even outside of the voice channels.
+    #[serde(default, skip_serializing_if = "MemberList::is_empty")]
+    pub allowlist: MemberList,
+    // Users and roles that can never see the text channels, even in the voice channels. Each of
+    // them gets an overwrite on the text channels that denies reading them. Someone can't be on
+    // both lists, but a user on it whose role is on the allowlist is still kept out.
+    #[serde(default, skip_serializing_if = "MemberList::is_empty")]
+    pub denylist: MemberList,
+}
+
+impl Room {
+    // new creates a room linked by hand.
+    pub fn new(voice_ids: Vec<ChannelId>, text_ids: Vec<ChannelId>) -> Room {
+        Room {
+            id: room_id(),
+            voice_ids,
+            text_ids,
+            archived_ids: Vec::new(),
+            category_id: None,
+            kind: RoomKind::Linked,
+            access: Access::Overwrites,
+            permissions: PermissionTemplate::default(),
+            allowlist: MemberList::default(),
+            denylist: MemberList::default(),
+        }
+    }
+}
+
+// ROOM_IDS tells apart the IDs of rooms made within the same microsecond.
+static ROOM_IDS: AtomicU64 = AtomicU64::new(0);
+
+// room_id makes a new ID for a room out of the time and a counter, so it's unique across restarts.
+pub fn room_id() -> u64 {
+    let micros = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as u64);
+    (micros << 12) | (ROOM_IDS.fetch_add(1, Ordering::Relaxed) & 0xfff)
+}
+
+// RoomKind is where the text channels of a room come from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomKind {
+    // The text channels already existed and were linked.
+    Linked,
+    // The text channel is made by the bot when the first person joins the voice channel. Once
+    // everyone left it's deleted, or just unlinked and left hidden if archive is set (see
+    // Room::archived_ids).
+    Auto { archive: bool },
+    // The voice channel and text channel were made by the bot for someone that joined a hub.
+    // Both are deleted once everyone left.
+    Temporary { owner: UserId },
+}
+
+impl RoomKind {
+    fn is_linked(&self) -> bool {
+        *self == RoomKind::Linked
+    }
+}
+
+impl Default for RoomKind {
+    fn default() -> Self {
+        RoomKind::Linked
+    }
+}
+
+// CategoryLink pairs every voice channel in a category with the text channel in the same category
+// whose name matches pattern. "{name}" in the pattern is replaced with the voice channel's name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CategoryLink {
+    pub category_id: ChannelId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+}
+
+// one_or_many reads either a single channel ID or a list of them.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<ChannelId>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(ChannelId),
+        Many(Vec<ChannelId>),
+    }
+
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(id) => Ok(vec![id]),
+        OneOrMany::Many(ids) => Ok(ids),
+    }
+}
+
+// Access is how the people in a room's voice channels are let into its text channels.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    // Each of them gets their own overwrite on the text channels.
+    Overwrites,
+    // They're given a role managed by the bot, the text channels only have an overwrite for
+    // the role.
+    Role { role_id: RoleId },
+}
+
+impl Access {
+    fn is_overwrites(&self) -> bool {
+        *self == Access::Overwrites
+    }
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Access::Overwrites
+    }
+}
+
+// PermissionTemplate is what a room's overwrites allow and deny on its text channels. Reading the
+// text channels is always allowed on top of it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PermissionTemplate {
+    pub allow: Permissions,
+    pub deny: Permissions,
+}
+
+impl PermissionTemplate {
+    // overwrite makes the overwrite that lets a member or role into a text channel.
+    pub fn overwrite(&self, kind: PermissionOverwriteType) -> PermissionOverwrite {
+        PermissionOverwrite {
+            allow: self.allow | Permissions::READ_MESSAGES,
+            deny: self.deny - Permissions::READ_MESSAGES,
+            kind,
+        }
+    }
+
+    // matches checks if an overwrite is the one this template makes.
+    pub fn matches(&self, perm: &PermissionOverwrite) -> bool {
+        let expected = self.overwrite(perm.kind);
+        perm.allow == expected.allow && perm.deny == expected.deny
+    }
+
+    fn is_default(&self) -> bool {
+        *self == PermissionTemplate::default()
+    }
+}
+
+impl Default for PermissionTemplate {
+    fn default() -> Self {
+        PermissionTemplate {
+            allow: Permissions::READ_MESSAGES,
+            deny: Permissions::empty(),
+        }
+    }
+}
+
+// MemberList is a list of users and roles of a guild.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MemberList {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<UserId>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<RoleId>,
+}
+
+impl MemberList {
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.roles.is_empty()
+    }
+
+    // contains checks if a member is on the list, either themselves or one of their roles.
+    pub fn contains(&self, user_id: UserId, roles: &[RoleId]) -> bool {
+        self.users.contains(&user_id) || roles.iter().any(|role_id| self.roles.contains(role_id))
+    }
+}
+
+// Storage selects the RoomStore that keeps every guild's rooms.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    Yaml { path: String },
+    Sqlite { path: String },
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Storage::Yaml {
+            path: DEFAULT_ROOMS_LOCATION.to_string(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub token: String,
     pub prefix: String,
+    #[serde(default)]
+    pub storage: Storage,
+    // How many previous versions of the config and rooms files are kept around.
+    #[serde(default = "default_backups")]
+    pub backups: usize,
+    // Syncing rooms only reports what it would change in every guild, see Serving::dry_run.
+    #[serde(default)]
+    pub dry_run: bool,
+    // Older versions kept the rooms in the config. They're only read so they can be moved over
+    // to the store, see store::migrate.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub serving: BTreeMap<u64, Serving>,
+    // Where the config.yml is.
+    #[serde(skip)]
+    path: String,
+    // What the config.yml held before Overrides replaced them, save writes these instead so an
+    // overridden token or prefix never ends up on disk.
+    #[serde(skip)]
+    file_token: Option<String>,
+    #[serde(skip)]
+    file_prefix: Option<String>,
+}
+
+// Overrides are settings given through the environment or the command line. They take priority
+// over the config.yml.
+#[derive(Debug, Default)]
+pub struct Overrides {
+    pub config: Option<String>,
+    pub token: Option<String>,
+    pub prefix: Option<String>,
+}
+
+impl Overrides {
+    // from_env reads the CONFIG_PATH, ROOMS_TOKEN and ROOMS_PREFIX environment variables and then
+    // the --config, --token and --prefix flags. Flags win over environment variables.
+    pub fn from_env() -> Result<Overrides, String> {
+        let mut overrides = Overrides {
+            config: env::var(ENV_VAR).ok(),
+            token: env::var(TOKEN_VAR).ok(),
+            prefix: env::var(PREFIX_VAR).ok(),
+        };
+
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            // Flags can be given as "--flag value" or "--flag=value"
+            let (flag, value) = match arg.find('=') {
+                Some(i) => (arg[..i].to_string(), Some(arg[i + 1..].to_string())),
+                None => (arg.clone(), None),
+            };
+
+            let field = match flag.as_str() {
+                "--config" => &mut overrides.config,
+                "--token" => &mut overrides.token,
+                "--prefix" => &mut overrides.prefix,
+                _ => return Err(format!("Unknown argument {}", arg)),
+            };
+
+            match value.or_else(|| args.next()) {
+                Some(value) => *field = Some(value),
+                None => return Err(format!("{} needs a value", flag)),
+            }
+        }
+
+        Ok(overrides)
+    }
 }
 
 impl Config {
-    // new will create a new config.yml if one isn't present at the default or custom path.
-    pub fn new() -> Config {
-        let location = env::var(ENV_VAR).unwrap_or(DEFAULT_LOCATION.to_string());
-        match Config::retrieve() {
-            Some(conf) => conf,
-            None => {
+    // new will create a new config.yml if one isn't present at the default or custom path. A
+    // config.yml that exists but can't be read or parsed is an error, it's never overwritten.
+    // The overrides are applied on top of whatever the config.yml holds.
+    pub fn new(overrides: &Overrides) -> Result<Config, ConfigError> {
+        let location = overrides.config.clone().unwrap_or(DEFAULT_LOCATION.to_string());
+
+        let mut conf = match Config::retrieve(&location) {
+            Err(ConfigError::NotFound(_)) => {
                 let conf = Config {
                     token: String::new(),
                     prefix: String::from("!"),
+                    storage: Storage::default(),
+                    backups: DEFAULT_BACKUPS,
+                    dry_run: false,
                     serving: BTreeMap::new(),
+                    path: location.clone(),
+                    file_token: None,
+                    file_prefix: None,
                 };
-                conf.save();
+                conf.save().map_err(|why| ConfigError::Io(location.clone(), why))?;
                 info!("Created a new config at {}", &location);
-                return conf;
+                conf
             }
+            result => result?,
+        };
+
+        if let Some(token) = &overrides.token {
+            conf.file_token = Some(std::mem::replace(&mut conf.token, token.clone()));
+        }
+        if let Some(prefix) = &overrides.prefix {
+            conf.file_prefix = Some(std::mem::replace(&mut conf.prefix, prefix.clone()));
         }
+
+        Ok(conf)
     }
 
-    // save saves the current state of Config
-    pub fn save(&self) {
-        let location = env::var(ENV_VAR).unwrap_or(DEFAULT_LOCATION.to_string());
-        let serialized = serde_yaml::to_string(&self).expect("Failed to serialize config.");
-        match File::create(&location) {
-            Ok(mut file) => {
-                file.write_all(serialized.as_bytes())
-                    .expect("Failed to write to config");
-            }
-            Err(_) => {
-                panic!("Failed to save config to {}", &location);
+    // save saves the current state of Config, minus anything that came from Overrides.
+    pub fn save(&self) -> io::Result<()> {
+        let mut on_disk = self.clone();
+        if let Some(token) = &self.file_token {
+            on_disk.token = token.clone();
+        }
+        if let Some(prefix) = &self.file_prefix {
+            on_disk.prefix = prefix.clone();
+        }
+
+        let serialized = serde_yaml::to_string(&on_disk)
+            .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why))?;
+        write_atomic(&self.path, serialized.as_bytes(), self.backups)
+    }
+
+    // retrieve returns the config.yml at location
+    fn retrieve(location: &str) -> Result<Config, ConfigError> {
+        let mut file = match File::open(location) {
+            Ok(file) => file,
+            Err(why) if why.kind() == io::ErrorKind::NotFound => {
+                return Err(ConfigError::NotFound(location.to_string()));
             }
+            Err(why) => return Err(ConfigError::Io(location.to_string(), why)),
+        };
+
+        let mut contents = String::new();
+        if let Err(why) = file.read_to_string(&mut contents) {
+            return Err(ConfigError::Io(location.to_string(), why));
         }
+
+        let mut conf: Config = serde_yaml::from_str(&contents).map_err(|why| {
+            let (line, column) = match why.location() {
+                Some(at) => (at.line(), at.column()),
+                None => (0, 0),
+            };
+            ConfigError::Parse {
+                path: location.to_string(),
+                line,
+                column,
+                message: why.to_string(),
+            }
+        })?;
+        conf.path = location.to_string();
+        Ok(conf)
     }
+}
 
-    // retrieve returns the current config.yml
-    fn retrieve() -> Option<Config> {
-        let location = env::var(ENV_VAR).unwrap_or(DEFAULT_LOCATION.to_string());
-        match File::open(location) {
-            Ok(mut file) => {
-                let mut contents = String::new();
-                if let Err(_) = file.read_to_string(&mut contents) {
-                    return None;
-                };
+fn default_backups() -> usize {
+    DEFAULT_BACKUPS
+}
 
-                match serde_yaml::from_str(&contents) {
-                    Ok(deserialized) => Some(deserialized),
-                    Err(_) => None,
-                }
+// write_atomic replaces the file at path with contents without ever leaving it half-written. The
+// contents go to a temporary file first which is then renamed over path. The previous versions
+// are kept as path.1 (newest) through path.N (oldest) where N is backups.
+pub fn write_atomic(path: &str, contents: &[u8], backups: usize) -> io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    {
+        let mut file = File::create(&tmp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+    }
+
+    if backups > 0 && Path::new(path).exists() {
+        for i in (1..backups).rev() {
+            let older = format!("{}.{}", path, i);
+            if Path::new(&older).exists() {
+                fs::rename(&older, format!("{}.{}", path, i + 1))?;
             }
-            Err(_) => None,
         }
+        fs::copy(path, format!("{}.1", path))?;
     }
+
+    fs::rename(&tmp, path)
 }
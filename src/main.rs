@@ -1,9 +1,11 @@
 mod bot;
 mod config;
+mod store;
 
-use crate::config::Config;
-use log::{info, warn, LevelFilter};
+use crate::config::{Config, Overrides};
+use log::{error, info, warn, LevelFilter};
 use simple_logger::SimpleLogger;
+use std::process;
 
 #[tokio::main]
 async fn main() {
@@ -12,12 +14,39 @@ async fn main() {
         .init()
         .unwrap();
     info!("Starting...");
-    let config = Config::new();
+    let overrides = match Overrides::from_env() {
+        Ok(overrides) => overrides,
+        Err(why) => {
+            error!(
+                "{}\nUsage: rooms [--config <path>] [--token <token>] [--prefix <prefix>]",
+                why
+            );
+            process::exit(1);
+        }
+    };
+
+    let mut config = match Config::new(&overrides) {
+        Ok(config) => config,
+        Err(why) => {
+            error!("Failed to load the config\n{}", why);
+            process::exit(1);
+        }
+    };
 
     if config.token.is_empty() {
-        warn!("Please fill out the config.yml");
+        warn!("Please fill out the config.yml or set ROOMS_TOKEN");
         return;
     }
 
-    bot::start(config).await;
+    let store = match store::open(&config) {
+        Ok(store) => store,
+        Err(why) => {
+            error!("Failed to open the rooms\n{}", why);
+            process::exit(1);
+        }
+    };
+    store::migrate(&mut config, store.as_ref());
+    store::identify(store.as_ref());
+
+    bot::start(config, store).await;
 }
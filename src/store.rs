@@ -0,0 +1,163 @@
+mod sqlite;
+mod yaml;
+
+pub use sqlite::SqliteStore;
+pub use yaml::YamlStore;
+
+use crate::config::{room_id, Config, Serving, Storage};
+use log::{info, warn};
+use serenity::model::id::{ChannelId, GuildId, UserId};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+// StoreError is why a RoomStore failed to load or save a guild's rooms.
+#[derive(Debug)]
+pub enum StoreError {
+    Io(io::Error),
+    Yaml(serde_yaml::Error),
+    Json(serde_json::Error),
+    Sqlite(rusqlite::Error),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::Io(why) => write!(f, "failed to access the rooms file: {}", why),
+            StoreError::Yaml(why) => write!(f, "failed to read or write the rooms: {}", why),
+            StoreError::Json(why) => write!(f, "failed to read or write the rooms: {}", why),
+            StoreError::Sqlite(why) => write!(f, "failed to access the rooms database: {}", why),
+        }
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(why: io::Error) -> Self {
+        StoreError::Io(why)
+    }
+}
+
+impl From<serde_yaml::Error> for StoreError {
+    fn from(why: serde_yaml::Error) -> Self {
+        StoreError::Yaml(why)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(why: serde_json::Error) -> Self {
+        StoreError::Json(why)
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(why: rusqlite::Error) -> Self {
+        StoreError::Sqlite(why)
+    }
+}
+
+// Managed are the member overwrites the bot made on a room's text-channels, as (text-channel,
+// member) pairs. Only these are ever revoked. They change on nearly every sync, so they're kept
+// apart from the rooms and their backups.
+pub type Managed = BTreeSet<(ChannelId, UserId)>;
+
+// RoomStore keeps the rooms of every guild the bot is serving. The bot's secrets stay in the
+// config, everything the commands change goes through here.
+pub trait RoomStore: Send + Sync {
+    // get returns the rooms of a guild if it has any.
+    fn get(&self, guild_id: GuildId) -> Result<Option<Serving>, StoreError>;
+
+    // put saves the rooms of a guild, replacing what was there before.
+    fn put(&self, serving: &Serving) -> Result<(), StoreError>;
+
+    // all returns every guild being served.
+    fn all(&self) -> Result<Vec<Serving>, StoreError>;
+
+    // managed returns the member overwrites the bot made on the text-channels of a room.
+    fn managed(&self, room_id: u64) -> Result<Managed, StoreError>;
+
+    // put_managed saves the member overwrites the bot made on the text-channels of a room.
+    fn put_managed(&self, room_id: u64, managed: &Managed) -> Result<(), StoreError>;
+
+    // find is get for those that can only carry on without the rooms if they couldn't be loaded.
+    // The error is logged and treated like the guild has no rooms, so nothing is saved over them.
+    fn find(&self, guild_id: GuildId) -> Option<Serving> {
+        match self.get(guild_id) {
+            Ok(serving) => serving,
+            Err(why) => {
+                warn!("Failed to get the rooms of {} because\n{}", guild_id, why);
+                None
+            }
+        }
+    }
+}
+
+// Store is the key of the RoomStore in the client's data.
+pub struct Store;
+
+// open returns the RoomStore selected in the config.
+pub fn open(config: &Config) -> Result<Arc<dyn RoomStore>, StoreError> {
+    match &config.storage {
+        Storage::Yaml { path } => Ok(Arc::new(YamlStore::new(path, config.backups)?)),
+        Storage::Sqlite { path } => Ok(Arc::new(SqliteStore::new(path)?)),
+    }
+}
+
+// identify gives the rooms saved by older versions an ID, see Room::id.
+pub fn identify(store: &dyn RoomStore) {
+    let all;
+    match store.all() {
+        Ok(_all) => all = _all,
+        Err(why) => {
+            warn!("Failed to give the rooms an ID because\n{}", why);
+            return;
+        }
+    }
+
+    for mut serving in all {
+        let mut identified = 0;
+        for room in serving.rooms.iter_mut().filter(|room| room.id == 0) {
+            room.id = room_id();
+            identified += 1;
+        }
+        if identified == 0 {
+            continue;
+        }
+
+        match store.put(&serving) {
+            Ok(()) => info!("Gave {} rooms of {} an ID", identified, serving.guild_id),
+            Err(why) => warn!("Failed to save the rooms of {} because\n{}", serving.guild_id, why),
+        }
+    }
+}
+
+// migrate moves the rooms older versions kept in the config over to the store.
+pub fn migrate(config: &mut Config, store: &dyn RoomStore) {
+    if config.serving.is_empty() {
+        return;
+    }
+
+    for serving in config.serving.values() {
+        match store.get(serving.guild_id) {
+            Ok(Some(_)) => continue,
+            Ok(None) => {}
+            Err(why) => {
+                warn!("Failed to move rooms out of the config because\n{}", why);
+                return;
+            }
+        }
+        if let Err(why) = store.put(serving) {
+            warn!("Failed to move rooms out of the config because\n{}", why);
+            return;
+        }
+    }
+
+    info!(
+        "Moved the rooms of {} guilds from the config to the store",
+        config.serving.len()
+    );
+    config.serving.clear();
+    if let Err(why) = config.save() {
+        warn!("Failed to save the config because\n{}", why);
+    }
+}
@@ -0,0 +1,118 @@
+use crate::config::Serving;
+use crate::store::{Managed, RoomStore, StoreError};
+use log::warn;
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+use serde_json;
+use serenity::model::id::{ChannelId, GuildId, UserId};
+use std::sync::Mutex;
+
+// SqliteStore keeps every guild's rooms in an embedded SQLite database, one row per guild. The
+// rooms of a guild are stored as JSON so the schema doesn't change every time a Room does. The
+// managed overwrites have a table of their own, one row per overwrite.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    // new opens (or creates) the database at path.
+    pub fn new(path: &str) -> Result<SqliteStore, StoreError> {
+        let conn = Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS serving (
+                guild_id INTEGER PRIMARY KEY,
+                data TEXT NOT NULL
+            )",
+            NO_PARAMS,
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS managed (
+                room_id INTEGER NOT NULL,
+                text_id INTEGER NOT NULL,
+                user_id INTEGER NOT NULL,
+                PRIMARY KEY (room_id, text_id, user_id)
+            )",
+            NO_PARAMS,
+        )?;
+
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+impl RoomStore for SqliteStore {
+    fn get(&self, guild_id: GuildId) -> Result<Option<Serving>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let data: Option<String> = conn
+            .query_row(
+                "SELECT data FROM serving WHERE guild_id = ?1",
+                params![*guild_id.as_u64() as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match data {
+            Some(data) => Ok(Some(serde_json::from_str(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put(&self, serving: &Serving) -> Result<(), StoreError> {
+        let data = serde_json::to_string(serving)?;
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO serving (guild_id, data) VALUES (?1, ?2)",
+            params![*serving.guild_id.as_u64() as i64, data],
+        )?;
+        Ok(())
+    }
+
+    // A guild whose rooms can't be parsed is left out and logged rather than failing every other
+    // guild with it.
+    fn all(&self) -> Result<Vec<Serving>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT guild_id, data FROM serving")?;
+        let rows = stmt.query_map(NO_PARAMS, |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let mut all = Vec::new();
+        for row in rows {
+            let (guild_id, data) = row?;
+            match serde_json::from_str(&data) {
+                Ok(serving) => all.push(serving),
+                Err(why) => warn!("Failed to parse the rooms of {} because\n{}", guild_id, why),
+            }
+        }
+        Ok(all)
+    }
+
+    fn managed(&self, room_id: u64) -> Result<Managed, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT text_id, user_id FROM managed WHERE room_id = ?1")?;
+        let rows = stmt.query_map(params![room_id as i64], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
+        })?;
+
+        let mut managed = Managed::new();
+        for row in rows {
+            let (text_id, user_id) = row?;
+            managed.insert((ChannelId(text_id as u64), UserId(user_id as u64)));
+        }
+        Ok(managed)
+    }
+
+    fn put_managed(&self, room_id: u64, managed: &Managed) -> Result<(), StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM managed WHERE room_id = ?1", params![room_id as i64])?;
+        for (text_id, user_id) in managed.iter() {
+            tx.execute(
+                "INSERT INTO managed (room_id, text_id, user_id) VALUES (?1, ?2, ?3)",
+                params![room_id as i64, *text_id.as_u64() as i64, *user_id.as_u64() as i64],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
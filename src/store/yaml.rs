@@ -0,0 +1,103 @@
+use crate::config::{write_atomic, Serving};
+use crate::store::{Managed, RoomStore, StoreError};
+use serde::de::DeserializeOwned;
+use serde_yaml;
+use serenity::model::id::GuildId;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::path::Path;
+use std::sync::RwLock;
+
+// YamlStore keeps every guild's rooms in a single YAML file. The whole file is rewritten whenever
+// a guild changes. The managed overwrites are kept next to it in their own file without backups,
+// e.g. rooms.managed.yml, since they're saved on nearly every sync.
+pub struct YamlStore {
+    path: String,
+    backups: usize,
+    serving: RwLock<BTreeMap<u64, Serving>>,
+    managed_path: String,
+    managed: RwLock<BTreeMap<u64, Managed>>,
+}
+
+// load parses the YAML file at path, or returns the default if there's no file yet.
+fn load<T: DeserializeOwned + Default>(path: &str) -> Result<T, StoreError> {
+    match File::open(path) {
+        Ok(mut file) => {
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            Ok(serde_yaml::from_str(&contents)?)
+        }
+        Err(why) if why.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(why) => Err(why.into()),
+    }
+}
+
+impl YamlStore {
+    // new loads the rooms at path, or starts empty if there's no file yet. A rooms file that
+    // exists but can't be read or parsed is an error, it's never overwritten.
+    pub fn new(path: &str, backups: usize) -> Result<YamlStore, StoreError> {
+        let managed_path = Path::new(path).with_extension("managed.yml");
+        let managed_path = managed_path.to_string_lossy().to_string();
+
+        Ok(YamlStore {
+            path: path.to_string(),
+            backups,
+            serving: RwLock::new(load(path)?),
+            managed: RwLock::new(load(&managed_path)?),
+            managed_path,
+        })
+    }
+
+    fn save(&self, serving: &BTreeMap<u64, Serving>) -> Result<(), StoreError> {
+        let serialized = serde_yaml::to_string(serving)?;
+        write_atomic(&self.path, serialized.as_bytes(), self.backups)?;
+        Ok(())
+    }
+}
+
+impl RoomStore for YamlStore {
+    fn get(&self, guild_id: GuildId) -> Result<Option<Serving>, StoreError> {
+        let serving = self.serving.read().unwrap();
+        Ok(serving.get(guild_id.as_u64()).cloned())
+    }
+
+    // The rooms are only updated in memory once they're on disk, so a failed save doesn't leave
+    // the two out of sync.
+    fn put(&self, serving: &Serving) -> Result<(), StoreError> {
+        let mut all = self.serving.write().unwrap();
+        let mut updated = all.clone();
+        updated.insert(*serving.guild_id.as_u64(), serving.clone());
+        self.save(&updated)?;
+        *all = updated;
+        Ok(())
+    }
+
+    fn all(&self) -> Result<Vec<Serving>, StoreError> {
+        let serving = self.serving.read().unwrap();
+        Ok(serving.values().cloned().collect())
+    }
+
+    fn managed(&self, room_id: u64) -> Result<Managed, StoreError> {
+        let managed = self.managed.read().unwrap();
+        Ok(managed.get(&room_id).cloned().unwrap_or_default())
+    }
+
+    fn put_managed(&self, room_id: u64, managed: &Managed) -> Result<(), StoreError> {
+        let mut all = self.managed.write().unwrap();
+        let mut updated = all.clone();
+        if managed.is_empty() {
+            updated.remove(&room_id);
+        } else {
+            updated.insert(room_id, managed.clone());
+        }
+        if updated == *all {
+            return Ok(());
+        }
+
+        let serialized = serde_yaml::to_string(&updated)?;
+        write_atomic(&self.managed_path, serialized.as_bytes(), 0)?;
+        *all = updated;
+        Ok(())
+    }
+}